@@ -0,0 +1,854 @@
+use std::{collections::VecDeque, fmt};
+
+use crate::audio::{
+    noise::Noise,
+    pulse::{Pulse, Sweep, SweepStep},
+    wave::Wave,
+};
+use crate::memory::AudioRegisters;
+use crate::savestate::{SaveState, SaveStateError, StateReader, StateWriter};
+
+mod noise;
+mod pulse;
+mod recorder;
+mod wave;
+
+pub use recorder::{AudioSource, Recording};
+
+/// T-cycles per second, the rate the channels are clocked at.
+pub const CLOCK_RATE: u32 = 4_194_304;
+const DEFAULT_SAMPLE_RATE: u32 = 48_000;
+/// Frames kept for the frontend before the oldest are dropped.
+const SAMPLE_BUFFER_FRAMES: usize = 16_384;
+/// Share of the high pass filter's charge that is left after a T-cycle.
+const HIGH_PASS_CHARGE: f64 = 0.999958;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum AudioError {
+    /// Sample rates have to be between 1 Hz and `CLOCK_RATE`.
+    SampleRate(u32),
+}
+impl fmt::Display for AudioError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AudioError::SampleRate(rate) => write!(
+                f,
+                "sample rate of {rate} Hz is outside 1 to {CLOCK_RATE} Hz"
+            ),
+        }
+    }
+}
+impl std::error::Error for AudioError {}
+
+fn check_sample_rate(sample_rate: u32) -> Result<(), AudioError> {
+    match (1..=CLOCK_RATE).contains(&sample_rate) {
+        true => Ok(()),
+        false => Err(AudioError::SampleRate(sample_rate)),
+    }
+}
+
+/// One sample for each speaker.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct StereoFrame {
+    pub left: i16,
+    pub right: i16,
+}
+
+/// Stops a channel once it has played for the length written to NRx1.
+#[derive(Copy, Clone, Default, PartialEq)]
+struct LengthCounter {
+    enabled: bool,
+    counter: u16,
+}
+impl LengthCounter {
+    fn load(&mut self, length: u16, max: u16) {
+        self.counter = max - length;
+    }
+    /// A channel triggered after running out plays for the full length.
+    fn trigger(&mut self, max: u16) {
+        if self.counter == 0 {
+            self.counter = max;
+        }
+    }
+    /// Returns whether the channel ran out and has to stop.
+    fn clock(&mut self) -> bool {
+        if !self.enabled || self.counter == 0 {
+            return false;
+        }
+        self.counter -= 1;
+        self.counter == 0
+    }
+}
+impl SaveState for LengthCounter {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_bool(self.enabled);
+        writer.write_u16(self.counter);
+    }
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), SaveStateError> {
+        self.enabled = reader.read_bool()?;
+        self.counter = reader.read_u16()?;
+        if self.counter > Wave::LENGTH {
+            return Err(SaveStateError::Corrupt("length counter out of range"));
+        }
+        Ok(())
+    }
+}
+
+/// Volume envelope of the pulse and noise channels, set up from NRx2 when
+/// the channel is triggered.
+#[derive(Copy, Clone, Default, PartialEq)]
+struct Envelope {
+    volume: u8,
+    increase: bool,
+    pace: u8,
+    timer: u8,
+}
+impl Envelope {
+    /// The DAC is off while the upper five bits of NRx2 are all clear.
+    fn dac_enabled(volume_and_envelope: u8) -> bool {
+        volume_and_envelope & 0b1111_1000 != 0
+    }
+    fn trigger(&mut self, volume_and_envelope: u8) {
+        self.volume = volume_and_envelope >> 4;
+        self.increase = volume_and_envelope & 0b1000 != 0;
+        self.pace = volume_and_envelope & 0b111;
+        self.timer = self.pace;
+    }
+    fn clock(&mut self) {
+        if self.pace == 0 {
+            return;
+        }
+        self.timer = self.timer.saturating_sub(1);
+        if self.timer != 0 {
+            return;
+        }
+        self.timer = self.pace;
+        match self.increase {
+            true if self.volume < 15 => self.volume += 1,
+            false if self.volume > 0 => self.volume -= 1,
+            _ => (),
+        }
+    }
+}
+impl SaveState for Envelope {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_u8(self.volume);
+        writer.write_bool(self.increase);
+        writer.write_u8(self.pace);
+        writer.write_u8(self.timer);
+    }
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), SaveStateError> {
+        self.volume = reader.read_u8()?;
+        if self.volume > 15 {
+            return Err(SaveStateError::Corrupt("envelope volume out of range"));
+        }
+        self.increase = reader.read_bool()?;
+        self.pace = reader.read_u8()? & 0b111;
+        self.timer = reader.read_u8()?;
+        Ok(())
+    }
+}
+
+/// The capacitor on each output that filters out the DACs' DC offset.
+#[derive(Copy, Clone, PartialEq)]
+struct HighPass {
+    capacitors: [f64; 2],
+    /// Share of the charge left after each frame.
+    charge: f64,
+}
+impl HighPass {
+    fn new(sample_rate: u32) -> Self {
+        Self {
+            capacitors: [0.0; 2],
+            charge: HIGH_PASS_CHARGE.powf(CLOCK_RATE as f64 / sample_rate as f64),
+        }
+    }
+    fn filter(&mut self, mixed: [f64; 2]) -> StereoFrame {
+        let [left, right] = [0, 1].map(|side| {
+            let output = mixed[side] - self.capacitors[side];
+            self.capacitors[side] = mixed[side] - output * self.charge;
+            (output.clamp(-1.0, 1.0) * i16::MAX as f64) as i16
+        });
+        StereoFrame { left, right }
+    }
+}
+
+/// The 11 bit period split over NRx3 and the low bits of NRx4.
+fn period(low: u8, high_and_control: u8) -> u16 {
+    ((high_and_control as u16 & 0b111) << 8) | low as u16
+}
+
+/// The four sound channels, mixed and resampled into stereo frames that the
+/// frontend pulls out with `pull_samples`.
+#[derive(Clone, PartialEq)]
+pub struct Apu {
+    pub registers: AudioRegisters,
+    sweep: Sweep,
+    channel1: Pulse,
+    channel2: Pulse,
+    channel3: Wave,
+    channel4: Noise,
+    /// Step 0-7 of the frame sequencer, which DIV clocks 512 times a second.
+    frame_sequencer: u8,
+    sample_rate: u32,
+    /// Goes up by the sample rate every T-cycle, a frame is taken each time
+    /// it passes `CLOCK_RATE`.
+    sample_phase: u32,
+    high_pass: HighPass,
+    samples: VecDeque<StereoFrame>,
+    recording: Option<Recording>,
+}
+
+impl Apu {
+    pub fn new() -> Self {
+        let mut apu = Self {
+            registers: AudioRegisters {
+                master_control: 0b1000_0000,
+                sound_panning: 0xF3,
+                master_volume_and_vin: 0x77,
+                channel_1_length_and_duty_cycle: 0x80,
+                channel_1_volume_and_envelope: 0xF3,
+                ..AudioRegisters::default()
+            },
+            sweep: Sweep::default(),
+            channel1: Pulse::default(),
+            channel2: Pulse::default(),
+            channel3: Wave::default(),
+            channel4: Noise::default(),
+            frame_sequencer: 0,
+            sample_rate: 0,
+            sample_phase: 0,
+            high_pass: HighPass::new(DEFAULT_SAMPLE_RATE),
+            samples: VecDeque::new(),
+            recording: None,
+        };
+        apu.set_sample_rate(DEFAULT_SAMPLE_RATE)
+            .expect("The default sample rate is in range");
+        apu
+    }
+    pub fn powered(&self) -> bool {
+        self.registers.master_control & 0b1000_0000 != 0
+    }
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+    /// Sets how many frames are made per second of emulated time. Rates
+    /// outside 1 Hz to `CLOCK_RATE` are refused and leave the old rate.
+    pub fn set_sample_rate(&mut self, sample_rate: u32) -> Result<(), AudioError> {
+        check_sample_rate(sample_rate)?;
+        self.sample_rate = sample_rate;
+        self.sample_phase = 0;
+        self.high_pass = HighPass::new(sample_rate);
+        Ok(())
+    }
+    /// Frames waiting to be pulled.
+    pub fn samples_available(&self) -> usize {
+        self.samples.len()
+    }
+    /// Moves the oldest waiting frames into `frames` and returns how many
+    /// were written.
+    pub fn pull_samples(&mut self, frames: &mut [StereoFrame]) -> usize {
+        let count = frames.len().min(self.samples.len());
+        for (frame, sample) in frames.iter_mut().zip(self.samples.drain(..count)) {
+            *frame = sample;
+        }
+        count
+    }
+    /// Starts a new recording, dropping any earlier one.
+    pub fn start_recording(&mut self, recording: Recording) {
+        self.recording = Some(recording);
+    }
+    pub fn recording(&self) -> Option<&Recording> {
+        self.recording.as_ref()
+    }
+    pub fn take_recording(&mut self) -> Option<Recording> {
+        self.recording.take()
+    }
+    pub fn read(&self, address: u16) -> u8 {
+        let registers = &self.registers;
+        match address {
+            0xFF10 => registers.channel_1_sweep | 0b1000_0000,
+            0xFF11 => registers.channel_1_length_and_duty_cycle | 0b0011_1111,
+            0xFF12 => registers.channel_1_volume_and_envelope,
+            0xFF14 => registers.channel_1_period_high_and_control | 0b1011_1111,
+            0xFF16 => registers.channel_2_length_and_duty_cycle | 0b0011_1111,
+            0xFF17 => registers.channel_2_volume_and_envelope,
+            0xFF19 => registers.channel_2_period_high_and_control | 0b1011_1111,
+            0xFF1A => registers.channel_3_dac_enable | 0b0111_1111,
+            0xFF1C => registers.channel_3_output_level | 0b1001_1111,
+            0xFF1E => registers.channel_3_period_high_and_control | 0b1011_1111,
+            0xFF21 => registers.channel_4_volume_and_envelope,
+            0xFF22 => registers.channel_4_frequency_and_randomness,
+            0xFF23 => registers.channel_4_control | 0b1011_1111,
+            0xFF24 => registers.master_volume_and_vin,
+            0xFF25 => registers.sound_panning,
+            0xFF26 => {
+                let status = [
+                    self.channel1.enabled,
+                    self.channel2.enabled,
+                    self.channel3.enabled,
+                    self.channel4.enabled,
+                ]
+                .iter()
+                .enumerate()
+                .fold(0, |status, (bit, enabled)| status | (*enabled as u8) << bit);
+                registers.master_control | 0b0111_0000 | status
+            }
+            0xFF30..=0xFF3F => registers.wave_pattern_ram[(address - 0xFF30) as usize],
+            // Periods, the lengths of channels 3 and 4 and the unused
+            // addresses can't be read.
+            _ => 0xFF,
+        }
+    }
+    pub fn write(&mut self, address: u16, value: u8) {
+        // Only NR52 and wave RAM can be written while the APU is off.
+        if !self.powered() && !matches!(address, 0xFF26 | 0xFF30..=0xFF3F) {
+            return;
+        }
+        let registers = &mut self.registers;
+        match address {
+            0xFF10 => registers.channel_1_sweep = value,
+            0xFF11 => {
+                registers.channel_1_length_and_duty_cycle = value;
+                self.channel1
+                    .length
+                    .load(value as u16 & 0b0011_1111, Pulse::LENGTH);
+            }
+            0xFF12 => {
+                registers.channel_1_volume_and_envelope = value;
+                self.channel1.enabled &= Envelope::dac_enabled(value);
+            }
+            0xFF13 => registers.channel_1_period_low = value,
+            0xFF14 => {
+                registers.channel_1_period_high_and_control = value;
+                self.channel1.length.enabled = value & 0b0100_0000 != 0;
+                if value & 0b1000_0000 != 0 {
+                    let period = period(registers.channel_1_period_low, value);
+                    self.channel1
+                        .trigger(registers.channel_1_volume_and_envelope, period);
+                    if let SweepStep::Overflow =
+                        self.sweep.trigger(registers.channel_1_sweep, period)
+                    {
+                        self.channel1.enabled = false;
+                    }
+                }
+            }
+            0xFF16 => {
+                registers.channel_2_length_and_duty_cycle = value;
+                self.channel2
+                    .length
+                    .load(value as u16 & 0b0011_1111, Pulse::LENGTH);
+            }
+            0xFF17 => {
+                registers.channel_2_volume_and_envelope = value;
+                self.channel2.enabled &= Envelope::dac_enabled(value);
+            }
+            0xFF18 => registers.channel_2_period_low = value,
+            0xFF19 => {
+                registers.channel_2_period_high_and_control = value;
+                self.channel2.length.enabled = value & 0b0100_0000 != 0;
+                if value & 0b1000_0000 != 0 {
+                    self.channel2.trigger(
+                        registers.channel_2_volume_and_envelope,
+                        period(registers.channel_2_period_low, value),
+                    );
+                }
+            }
+            0xFF1A => {
+                registers.channel_3_dac_enable = value;
+                self.channel3.enabled &= Wave::dac_enabled(value);
+            }
+            0xFF1B => {
+                registers.channel_3_length_timer = value;
+                self.channel3.length.load(value as u16, Wave::LENGTH);
+            }
+            0xFF1C => registers.channel_3_output_level = value,
+            0xFF1D => registers.channel_3_period_low = value,
+            0xFF1E => {
+                registers.channel_3_period_high_and_control = value;
+                self.channel3.length.enabled = value & 0b0100_0000 != 0;
+                if value & 0b1000_0000 != 0 {
+                    self.channel3.trigger(
+                        registers.channel_3_dac_enable,
+                        period(registers.channel_3_period_low, value),
+                    );
+                }
+            }
+            0xFF20 => {
+                registers.channel_4_length_timer = value;
+                self.channel4
+                    .length
+                    .load(value as u16 & 0b0011_1111, Noise::LENGTH);
+            }
+            0xFF21 => {
+                registers.channel_4_volume_and_envelope = value;
+                self.channel4.enabled &= Envelope::dac_enabled(value);
+            }
+            0xFF22 => registers.channel_4_frequency_and_randomness = value,
+            0xFF23 => {
+                registers.channel_4_control = value;
+                self.channel4.length.enabled = value & 0b0100_0000 != 0;
+                if value & 0b1000_0000 != 0 {
+                    self.channel4.trigger(
+                        registers.channel_4_volume_and_envelope,
+                        registers.channel_4_frequency_and_randomness,
+                    );
+                }
+            }
+            0xFF24 => registers.master_volume_and_vin = value,
+            0xFF25 => registers.sound_panning = value,
+            0xFF26 => self.write_master_control(value),
+            0xFF30..=0xFF3F => registers.wave_pattern_ram[(address - 0xFF30) as usize] = value,
+            _ => (),
+        }
+    }
+    /// Turning the APU off clears every register apart from wave RAM and
+    /// silences all channels. Turning it back on restarts the frame sequencer.
+    fn write_master_control(&mut self, value: u8) {
+        let powered = value & 0b1000_0000 != 0;
+        match (self.powered(), powered) {
+            (true, false) => {
+                self.registers = AudioRegisters {
+                    wave_pattern_ram: self.registers.wave_pattern_ram,
+                    ..AudioRegisters::default()
+                };
+                self.sweep = Sweep::default();
+                self.channel1 = Pulse::default();
+                self.channel2 = Pulse::default();
+                self.channel3 = Wave::default();
+                self.channel4 = Noise::default();
+            }
+            (false, true) => self.frame_sequencer = 0,
+            _ => (),
+        }
+        self.registers.master_control = value & 0b1000_0000;
+    }
+    /// Lengths are clocked on even steps, the sweep on steps 2 and 6 and
+    /// the envelopes on step 7.
+    pub fn clock_frame_sequencer(&mut self) {
+        if !self.powered() {
+            return;
+        }
+        let step = self.frame_sequencer;
+        self.frame_sequencer = (step + 1) % 8;
+        if matches!(step, 0 | 2 | 4 | 6) {
+            self.channel1.enabled &= !self.channel1.length.clock();
+            self.channel2.enabled &= !self.channel2.length.clock();
+            self.channel3.enabled &= !self.channel3.length.clock();
+            self.channel4.enabled &= !self.channel4.length.clock();
+        }
+        if step == 2 || step == 6 {
+            match self.sweep.clock(self.registers.channel_1_sweep) {
+                SweepStep::Unchanged => (),
+                SweepStep::Period(period) => {
+                    let registers = &mut self.registers;
+                    registers.channel_1_period_low = period as u8;
+                    registers.channel_1_period_high_and_control =
+                        (registers.channel_1_period_high_and_control & !0b111)
+                            | (period >> 8) as u8;
+                }
+                SweepStep::Overflow => self.channel1.enabled = false,
+            }
+        }
+        if step == 7 {
+            self.channel1.clock_envelope();
+            self.channel2.clock_envelope();
+            self.channel4.clock_envelope();
+        }
+    }
+    /// Runs the channels for `cycles` T-cycles, taking frames as it goes.
+    pub fn tick(&mut self, cycles: u32) {
+        for _ in 0..cycles {
+            if self.powered() {
+                self.tick_channels();
+            }
+            self.sample_phase += self.sample_rate;
+            if self.sample_phase >= CLOCK_RATE {
+                self.sample_phase -= CLOCK_RATE;
+                let frame = self.high_pass.filter(self.mix(0b1111));
+                if self.samples.len() == SAMPLE_BUFFER_FRAMES {
+                    self.samples.pop_front();
+                }
+                self.samples.push_back(frame);
+            }
+            if let Some(channels) = self.recording.as_mut().and_then(Recording::tick) {
+                let mixed = self.mix(channels);
+                if let Some(recording) = &mut self.recording {
+                    recording.push(mixed);
+                }
+            }
+        }
+    }
+    fn tick_channels(&mut self) {
+        let registers = &self.registers;
+        if self.channel1.enabled {
+            self.channel1.tick(period(
+                registers.channel_1_period_low,
+                registers.channel_1_period_high_and_control,
+            ));
+        }
+        if self.channel2.enabled {
+            self.channel2.tick(period(
+                registers.channel_2_period_low,
+                registers.channel_2_period_high_and_control,
+            ));
+        }
+        if self.channel3.enabled {
+            self.channel3.tick(
+                period(
+                    registers.channel_3_period_low,
+                    registers.channel_3_period_high_and_control,
+                ),
+                &registers.wave_pattern_ram,
+            );
+        }
+        if self.channel4.enabled {
+            self.channel4
+                .tick(registers.channel_4_frequency_and_randomness);
+        }
+    }
+    /// What each channel's DAC puts out, from -1.0 to 1.0. A DAC that is
+    /// switched off puts out 0.0.
+    fn dac_outputs(&self) -> [f64; 4] {
+        let registers = &self.registers;
+        [
+            (
+                Envelope::dac_enabled(registers.channel_1_volume_and_envelope),
+                self.channel1
+                    .output(registers.channel_1_length_and_duty_cycle),
+            ),
+            (
+                Envelope::dac_enabled(registers.channel_2_volume_and_envelope),
+                self.channel2
+                    .output(registers.channel_2_length_and_duty_cycle),
+            ),
+            (
+                Wave::dac_enabled(registers.channel_3_dac_enable),
+                self.channel3.output(registers.channel_3_output_level),
+            ),
+            (
+                Envelope::dac_enabled(registers.channel_4_volume_and_envelope),
+                self.channel4.output(),
+            ),
+        ]
+        .map(|(enabled, output)| match enabled {
+            true => output as f64 / 7.5 - 1.0,
+            false => 0.0,
+        })
+    }
+    /// Mixes `channels`, one bit each, to the sides NR51 pans them to and
+    /// scales each side by its NR50 volume.
+    fn mix(&self, channels: u8) -> [f64; 2] {
+        let dacs = self.dac_outputs();
+        let panning = self.registers.sound_panning & (channels << 4 | channels);
+        let volume = self.registers.master_volume_and_vin;
+        [(4, volume >> 4), (0, volume)].map(|(shift, volume)| {
+            let mixed: f64 = (0..4)
+                .filter(|channel| panning >> (shift + channel) & 1 != 0)
+                .map(|channel| dacs[channel as usize])
+                .sum();
+            mixed / 4.0 * ((volume & 0b111) + 1) as f64 / 8.0
+        })
+    }
+}
+
+impl Default for Apu {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Frames that haven't been pulled yet, the filter and any recording are
+/// left out, a loaded state starts with an empty buffer.
+impl SaveState for Apu {
+    fn save_state(&self, writer: &mut StateWriter) {
+        self.registers.save_state(writer);
+        self.sweep.save_state(writer);
+        self.channel1.save_state(writer);
+        self.channel2.save_state(writer);
+        self.channel3.save_state(writer);
+        self.channel4.save_state(writer);
+        writer.write_u8(self.frame_sequencer);
+    }
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), SaveStateError> {
+        self.registers.load_state(reader)?;
+        self.sweep.load_state(reader)?;
+        self.channel1.load_state(reader)?;
+        self.channel2.load_state(reader)?;
+        self.channel3.load_state(reader)?;
+        self.channel4.load_state(reader)?;
+        self.frame_sequencer = reader.read_u8()?;
+        if self.frame_sequencer >= 8 {
+            return Err(SaveStateError::Corrupt("frame sequencer step out of range"));
+        }
+        self.sample_phase = 0;
+        self.high_pass = HighPass::new(self.sample_rate);
+        self.samples.clear();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::{Memory, MemoryMap};
+    use std::time::Duration;
+
+    #[test]
+    fn test_power_off() {
+        let mut apu = Apu::new();
+        apu.write(0xFF12, 0xF0);
+        apu.write(0xFF11, 0x80);
+        apu.write(0xFF14, 0x80);
+        assert!(apu.read(0xFF26) == 0xF1);
+        apu.write(0xFF30, 0x12);
+
+        apu.write(0xFF26, 0x00);
+        assert!(apu.read(0xFF26) == 0x70);
+        assert!(apu.read(0xFF11) == 0x3F);
+        assert!(apu.read(0xFF12) == 0x00);
+        assert!(apu.read(0xFF25) == 0x00);
+        // Registers ignore writes while off, wave RAM doesn't.
+        apu.write(0xFF12, 0xF0);
+        assert!(apu.read(0xFF12) == 0x00);
+        apu.write(0xFF31, 0x34);
+        assert!(apu.read(0xFF30) == 0x12);
+        assert!(apu.read(0xFF31) == 0x34);
+
+        apu.write(0xFF26, 0x80);
+        assert!(apu.read(0xFF26) == 0xF0);
+        apu.write(0xFF12, 0xF0);
+        assert!(apu.read(0xFF12) == 0xF0);
+    }
+
+    #[test]
+    fn test_length_counter() {
+        let mut memory = MemoryMap::new();
+        memory.write(0xFF17, 0xF0);
+        memory.write(0xFF16, 0x3E);
+        memory.write(0xFF19, 0xC0);
+        assert!(memory.read(0xFF26) & 0b10 != 0);
+        // DIV bit 4 falls every 8192 T-cycles and lengths are clocked on
+        // every other step, starting with the first.
+        memory.tick_timer(16384);
+        assert!(memory.read(0xFF26) & 0b10 != 0);
+        memory.tick_timer(8192);
+        assert!(memory.read(0xFF26) & 0b10 == 0);
+
+        // Without length enabled the channel plays on.
+        memory.write(0xFF19, 0x80);
+        memory.tick_timer(65536);
+        assert!(memory.read(0xFF26) & 0b10 != 0);
+
+        // Resetting DIV while bit 4 is set clocks the frame sequencer too.
+        let mut memory = MemoryMap::new();
+        memory.write(0xFF17, 0xF0);
+        memory.write(0xFF16, 0x3F);
+        memory.write(0xFF19, 0xC0);
+        memory.tick_timer(4096);
+        assert!(memory.read(0xFF26) & 0b10 != 0);
+        memory.write(0xFF04, 0x00);
+        assert!(memory.read(0xFF26) & 0b10 == 0);
+    }
+
+    #[test]
+    fn test_sweep() {
+        let mut apu = Apu::new();
+        apu.write(0xFF10, 0x11);
+        apu.write(0xFF12, 0xF0);
+        apu.write(0xFF13, 0x00);
+        apu.write(0xFF14, 0x81);
+        for _ in 0..3 {
+            apu.clock_frame_sequencer();
+        }
+        assert!(apu.registers.channel_1_period_low == 0x80);
+        assert!(apu.registers.channel_1_period_high_and_control & 0b111 == 0x01);
+        assert!(apu.read(0xFF26) & 0b1 != 0);
+
+        // 0x700 plus half of it is past 2047, which is caught on trigger.
+        apu.write(0xFF13, 0x00);
+        apu.write(0xFF14, 0x87);
+        assert!(apu.read(0xFF26) & 0b1 == 0);
+
+        // Subtracting never overflows.
+        apu.write(0xFF10, 0x19);
+        apu.write(0xFF14, 0x87);
+        for _ in 0..4 {
+            apu.clock_frame_sequencer();
+        }
+        assert!(apu.registers.channel_1_period_low == 0x80);
+        assert!(apu.registers.channel_1_period_high_and_control & 0b111 == 0x03);
+        assert!(apu.read(0xFF26) & 0b1 != 0);
+    }
+
+    #[test]
+    fn test_envelope() {
+        let mut envelope = Envelope::default();
+        envelope.trigger(0x0B);
+        assert!(Envelope::dac_enabled(0x0B));
+        for _ in 0..5 {
+            envelope.clock();
+        }
+        assert!(envelope.volume == 1);
+        envelope.trigger(0x21);
+        for _ in 0..5 {
+            envelope.clock();
+        }
+        assert!(envelope.volume == 0);
+        // A pace of 0 holds the volume.
+        envelope.trigger(0xF0);
+        envelope.clock();
+        assert!(envelope.volume == 15);
+        assert!(!Envelope::dac_enabled(0x07));
+    }
+
+    fn noise_outputs(frequency_and_randomness: u8, steps: usize) -> Vec<u8> {
+        let mut apu = Apu::new();
+        apu.write(0xFF21, 0xF0);
+        apu.write(0xFF22, frequency_and_randomness);
+        apu.write(0xFF23, 0x80);
+        (0..steps)
+            .map(|_| {
+                apu.tick(8);
+                apu.channel4.output()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_noise_lfsr() {
+        // Starting from all ones the first 14 shifts leave bit 0 set.
+        let outputs = noise_outputs(0x00, 0x8000 * 2);
+        assert!(outputs[..14].iter().all(|output| *output == 0));
+        assert!(outputs[14] == 15);
+        assert!(outputs[..0x7FFF] == outputs[0x7FFF..0x7FFF * 2]);
+        assert!(outputs[..0x3FFF] != outputs[0x3FFF..0x3FFF * 2]);
+
+        // The short mode repeats every 127 shifts.
+        let outputs = noise_outputs(0x08, 127 * 2);
+        assert!(outputs[..6].iter().all(|output| *output == 0));
+        assert!(outputs[6] == 15);
+        assert!(outputs[..127] == outputs[127..]);
+
+        // Shifts of 14 and 15 stop the LFSR.
+        let outputs = noise_outputs(0xE0, 4 << 14);
+        assert!(outputs.iter().all(|output| *output == 0));
+    }
+
+    #[test]
+    fn test_wave_output() {
+        let mut apu = Apu::new();
+        for (address, byte) in (0xFF30..).zip([0x0F, 0x84]) {
+            apu.write(address, byte);
+        }
+        apu.write(0xFF1A, 0x80);
+        apu.write(0xFF1C, 0x20);
+        apu.write(0xFF1D, 0xFF);
+        apu.write(0xFF1E, 0x87);
+        // A period of 0x7FF reads the next sample every 2 T-cycles, starting
+        // with the second.
+        let mut outputs = Vec::new();
+        for _ in 0..3 {
+            apu.tick(2);
+            outputs.push(apu.channel3.output(apu.registers.channel_3_output_level));
+        }
+        assert!(outputs == [0x0F, 0x08, 0x04]);
+        apu.write(0xFF1C, 0x40);
+        assert!(apu.channel3.output(apu.registers.channel_3_output_level) == 0x02);
+        apu.write(0xFF1C, 0x00);
+        assert!(apu.channel3.output(apu.registers.channel_3_output_level) == 0x00);
+        apu.write(0xFF1A, 0x00);
+        assert!(apu.read(0xFF26) & 0b100 == 0);
+    }
+
+    #[test]
+    fn test_samples() {
+        let mut apu = Apu::new();
+        assert!(apu.set_sample_rate(0) == Err(AudioError::SampleRate(0)));
+        assert!(apu.set_sample_rate(CLOCK_RATE + 1) == Err(AudioError::SampleRate(CLOCK_RATE + 1)));
+        assert!(apu.sample_rate() == DEFAULT_SAMPLE_RATE);
+        apu.set_sample_rate(1000).unwrap();
+        // Channel 2 on the left only.
+        apu.write(0xFF25, 0x20);
+        apu.write(0xFF17, 0xF0);
+        apu.write(0xFF16, 0x80);
+        apu.write(0xFF18, 0x00);
+        apu.write(0xFF19, 0x87);
+        apu.tick(CLOCK_RATE);
+        assert!(apu.samples_available() == 1000);
+
+        let mut frames = [StereoFrame::default(); 600];
+        assert!(apu.pull_samples(&mut frames) == 600);
+        assert!(apu.samples_available() == 400);
+        assert!(frames.iter().all(|frame| frame.right == 0));
+        assert!(frames.iter().any(|frame| frame.left > 0));
+        assert!(frames.iter().any(|frame| frame.left < 0));
+        assert!(apu.pull_samples(&mut frames) == 400);
+        assert!(apu.pull_samples(&mut frames) == 0);
+    }
+
+    #[test]
+    fn test_recording() {
+        let mut apu = Apu::new();
+        // Channel 2 at full volume on both sides, channel 4's DAC off.
+        apu.write(0xFF25, 0x22);
+        apu.write(0xFF17, 0xF0);
+        apu.write(0xFF16, 0x80);
+        apu.write(0xFF19, 0x87);
+        apu.start_recording(
+            Recording::new(AudioSource::Channel2, 8000, Duration::from_millis(10)).unwrap(),
+        );
+        // Twice the length, the recording stops by itself after 10 ms.
+        apu.tick(CLOCK_RATE / 50);
+        assert!(apu.recording().unwrap().is_finished());
+        let recording = apu.take_recording().unwrap();
+        assert!(apu.recording().is_none());
+        assert!(recording.frames().len() == 80);
+        assert!(recording.frames().iter().any(|frame| frame.left != 0));
+        assert!(
+            recording
+                .frames()
+                .iter()
+                .all(|frame| frame.left == frame.right)
+        );
+
+        let mut wav = Vec::new();
+        recording.write_wav(&mut wav).unwrap();
+        assert!(wav.len() == 44 + 80 * 4);
+        assert!(wav[..4] == *b"RIFF");
+        assert!(wav[4..8] == (36_u32 + 80 * 4).to_le_bytes());
+        assert!(wav[8..16] == *b"WAVEfmt ");
+        // PCM, two channels, 8000 Hz, 32000 bytes a second, 4 byte frames,
+        // 16 bits.
+        assert!(wav[20..24] == [0x01, 0x00, 0x02, 0x00]);
+        assert!(wav[24..28] == 8000_u32.to_le_bytes());
+        assert!(wav[28..32] == 32000_u32.to_le_bytes());
+        assert!(wav[32..36] == [0x04, 0x00, 0x10, 0x00]);
+        assert!(wav[36..40] == *b"data");
+        assert!(wav[40..44] == (80_u32 * 4).to_le_bytes());
+        assert!(wav[44..46] == recording.frames()[0].left.to_le_bytes());
+
+        apu.start_recording(
+            Recording::new(AudioSource::Channel4, 8000, Duration::from_millis(5)).unwrap(),
+        );
+        apu.tick(CLOCK_RATE / 100);
+        assert!(apu.recording().unwrap().is_finished());
+        let recording = apu.take_recording().unwrap();
+        assert!(recording.frames().len() == 40);
+        assert!(
+            recording
+                .frames()
+                .iter()
+                .all(|frame| *frame == StereoFrame::default())
+        );
+
+        let length = Duration::from_secs(1);
+        assert!(matches!(
+            Recording::new(AudioSource::Mixed, 0, length),
+            Err(AudioError::SampleRate(0))
+        ));
+        let recording = Recording::new(AudioSource::Mixed, 8000, Duration::MAX).unwrap();
+        assert!(!recording.is_finished());
+        assert!(recording.frames().is_empty());
+    }
+}
@@ -0,0 +1,75 @@
+use crate::audio::{Envelope, LengthCounter};
+use crate::savestate::{SaveState, SaveStateError, StateReader, StateWriter};
+
+/// Channel 4, white noise from a linear feedback shift register.
+#[derive(Copy, Clone, Default, PartialEq)]
+pub struct Noise {
+    pub enabled: bool,
+    pub length: LengthCounter,
+    envelope: Envelope,
+    /// T-cycles until the LFSR is shifted again.
+    timer: u32,
+    lfsr: u16,
+}
+impl Noise {
+    pub const LENGTH: u16 = 64;
+    /// Cycles between shifts set by the divisor and shift in NR43.
+    fn period_cycles(frequency_and_randomness: u8) -> u32 {
+        let divisor = match frequency_and_randomness & 0b111 {
+            0 => 8,
+            divisor => divisor as u32 * 16,
+        };
+        divisor << (frequency_and_randomness >> 4)
+    }
+    pub fn trigger(&mut self, volume_and_envelope: u8, frequency_and_randomness: u8) {
+        self.enabled = Envelope::dac_enabled(volume_and_envelope);
+        self.length.trigger(Self::LENGTH);
+        self.envelope.trigger(volume_and_envelope);
+        self.timer = Self::period_cycles(frequency_and_randomness);
+        self.lfsr = 0x7FFF;
+    }
+    pub fn tick(&mut self, frequency_and_randomness: u8) {
+        self.timer = self.timer.saturating_sub(1);
+        if self.timer != 0 {
+            return;
+        }
+        self.timer = Self::period_cycles(frequency_and_randomness);
+        // Shifts of 14 and 15 never clock the LFSR.
+        if frequency_and_randomness >> 4 >= 14 {
+            return;
+        }
+        let feedback = (self.lfsr ^ (self.lfsr >> 1)) & 1;
+        self.lfsr = (self.lfsr >> 1) | (feedback << 14);
+        // In 7 bit mode the feedback goes into bit 6 as well.
+        if frequency_and_randomness & 0b1000 != 0 {
+            self.lfsr = (self.lfsr & !(1 << 6)) | (feedback << 6);
+        }
+    }
+    pub fn clock_envelope(&mut self) {
+        self.envelope.clock();
+    }
+    /// Volume 0-15 the channel is putting out right now.
+    pub fn output(&self) -> u8 {
+        match self.enabled && self.lfsr & 1 == 0 {
+            true => self.envelope.volume,
+            false => 0,
+        }
+    }
+}
+impl SaveState for Noise {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_bool(self.enabled);
+        self.length.save_state(writer);
+        self.envelope.save_state(writer);
+        writer.write_u32(self.timer);
+        writer.write_u16(self.lfsr);
+    }
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), SaveStateError> {
+        self.enabled = reader.read_bool()?;
+        self.length.load_state(reader)?;
+        self.envelope.load_state(reader)?;
+        self.timer = reader.read_u32()?;
+        self.lfsr = reader.read_u16()? & 0x7FFF;
+        Ok(())
+    }
+}
@@ -0,0 +1,147 @@
+use crate::audio::{Envelope, LengthCounter};
+use crate::savestate::{SaveState, SaveStateError, StateReader, StateWriter};
+
+/// The 12.5%, 25%, 50% and 75% waveforms, played from the top bit down.
+const DUTY_CYCLES: [u8; 4] = [0b0000_0001, 0b1000_0001, 0b1000_0111, 0b0111_1110];
+
+/// Square wave channels 1 and 2.
+#[derive(Copy, Clone, Default, PartialEq)]
+pub struct Pulse {
+    pub enabled: bool,
+    pub length: LengthCounter,
+    envelope: Envelope,
+    /// T-cycles until the next step of the waveform.
+    timer: u16,
+    duty_step: u8,
+}
+impl Pulse {
+    pub const LENGTH: u16 = 64;
+    fn period_cycles(period: u16) -> u16 {
+        (2048 - period) * 4
+    }
+    pub fn trigger(&mut self, volume_and_envelope: u8, period: u16) {
+        self.enabled = Envelope::dac_enabled(volume_and_envelope);
+        self.length.trigger(Self::LENGTH);
+        self.envelope.trigger(volume_and_envelope);
+        self.timer = Self::period_cycles(period);
+    }
+    pub fn tick(&mut self, period: u16) {
+        self.timer = self.timer.saturating_sub(1);
+        if self.timer == 0 {
+            self.timer = Self::period_cycles(period);
+            self.duty_step = (self.duty_step + 1) % 8;
+        }
+    }
+    pub fn clock_envelope(&mut self) {
+        self.envelope.clock();
+    }
+    /// Volume 0-15 the channel is putting out right now.
+    pub fn output(&self, length_and_duty_cycle: u8) -> u8 {
+        let duty = DUTY_CYCLES[(length_and_duty_cycle >> 6) as usize];
+        match self.enabled && duty >> (7 - self.duty_step) & 1 != 0 {
+            true => self.envelope.volume,
+            false => 0,
+        }
+    }
+}
+impl SaveState for Pulse {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_bool(self.enabled);
+        self.length.save_state(writer);
+        self.envelope.save_state(writer);
+        writer.write_u16(self.timer);
+        writer.write_u8(self.duty_step);
+    }
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), SaveStateError> {
+        self.enabled = reader.read_bool()?;
+        self.length.load_state(reader)?;
+        self.envelope.load_state(reader)?;
+        self.timer = reader.read_u16()?;
+        self.duty_step = reader.read_u8()?;
+        if self.duty_step >= 8 {
+            return Err(SaveStateError::Corrupt("pulse duty step out of range"));
+        }
+        Ok(())
+    }
+}
+
+/// What a sweep step did to channel 1's period.
+pub enum SweepStep {
+    Unchanged,
+    Period(u16),
+    /// The period went past 2047, which silences the channel.
+    Overflow,
+}
+
+/// Channel 1's frequency sweep, driven by NR10.
+#[derive(Copy, Clone, Default, PartialEq)]
+pub struct Sweep {
+    enabled: bool,
+    /// Copy of the period the sweep works from.
+    shadow: u16,
+    timer: u8,
+}
+impl Sweep {
+    fn pace(sweep: u8) -> u8 {
+        (sweep >> 4) & 0b111
+    }
+    /// A pace of 0 reloads the timer with 8.
+    fn reload(sweep: u8) -> u8 {
+        match Self::pace(sweep) {
+            0 => 8,
+            pace => pace,
+        }
+    }
+    fn next_period(&self, sweep: u8) -> u16 {
+        let delta = self.shadow >> (sweep & 0b111);
+        match sweep & 0b1000 {
+            0 => self.shadow + delta,
+            _ => self.shadow - delta,
+        }
+    }
+    pub fn trigger(&mut self, sweep: u8, period: u16) -> SweepStep {
+        self.shadow = period;
+        self.timer = Self::reload(sweep);
+        self.enabled = sweep & 0b0111_0111 != 0;
+        match sweep & 0b111 != 0 && self.next_period(sweep) > 2047 {
+            true => SweepStep::Overflow,
+            false => SweepStep::Unchanged,
+        }
+    }
+    pub fn clock(&mut self, sweep: u8) -> SweepStep {
+        self.timer = self.timer.saturating_sub(1);
+        if self.timer != 0 {
+            return SweepStep::Unchanged;
+        }
+        self.timer = Self::reload(sweep);
+        if !self.enabled || Self::pace(sweep) == 0 {
+            return SweepStep::Unchanged;
+        }
+        let period = self.next_period(sweep);
+        if period > 2047 {
+            return SweepStep::Overflow;
+        }
+        if sweep & 0b111 == 0 {
+            return SweepStep::Unchanged;
+        }
+        self.shadow = period;
+        // The new period is checked again straight away, without being used.
+        match self.next_period(sweep) > 2047 {
+            true => SweepStep::Overflow,
+            false => SweepStep::Period(period),
+        }
+    }
+}
+impl SaveState for Sweep {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_bool(self.enabled);
+        writer.write_u16(self.shadow);
+        writer.write_u8(self.timer);
+    }
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), SaveStateError> {
+        self.enabled = reader.read_bool()?;
+        self.shadow = reader.read_u16()? & 0x07FF;
+        self.timer = reader.read_u8()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,123 @@
+use std::{
+    io::{self, Write},
+    time::Duration,
+};
+
+use crate::audio::{AudioError, CLOCK_RATE, HighPass, StereoFrame, check_sample_rate};
+
+const WAV_HEADER_SIZE: u32 = 44;
+const WAV_FORMAT_PCM: u16 = 1;
+const WAV_CHANNELS: u16 = 2;
+const WAV_BITS_PER_SAMPLE: u16 = 16;
+const WAV_BLOCK_ALIGN: u16 = WAV_CHANNELS * WAV_BITS_PER_SAMPLE / 8;
+
+/// The part of the APU's output a recording captures.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum AudioSource {
+    /// Everything, the same as the frontend hears.
+    Mixed,
+    /// A single channel, still panned by NR51 and scaled by NR50.
+    Channel1,
+    Channel2,
+    Channel3,
+    Channel4,
+}
+impl AudioSource {
+    /// The channels mixed into the recording, one bit each in NR51 order.
+    fn channels(self) -> u8 {
+        match self {
+            AudioSource::Mixed => 0b1111,
+            AudioSource::Channel1 => 0b0001,
+            AudioSource::Channel2 => 0b0010,
+            AudioSource::Channel3 => 0b0100,
+            AudioSource::Channel4 => 0b1000,
+        }
+    }
+}
+
+/// Audio captured straight from the APU at its own sample rate, apart from
+/// whatever the frontend pulls. Recording stops by itself once it reaches
+/// the length it was started with.
+#[derive(Clone, PartialEq)]
+pub struct Recording {
+    source: AudioSource,
+    sample_rate: u32,
+    sample_phase: u32,
+    high_pass: HighPass,
+    length: usize,
+    frames: Vec<StereoFrame>,
+}
+impl Recording {
+    /// Fails for sample rates outside 1 Hz to `CLOCK_RATE`. Frames are
+    /// stored as they are recorded, so a long `length` costs nothing up
+    /// front.
+    pub fn new(
+        source: AudioSource,
+        sample_rate: u32,
+        length: Duration,
+    ) -> Result<Self, AudioError> {
+        check_sample_rate(sample_rate)?;
+        // The cast saturates, so huge lengths just never finish.
+        let length = (length.as_secs_f64() * sample_rate as f64).round() as usize;
+        Ok(Self {
+            source,
+            sample_rate,
+            sample_phase: 0,
+            high_pass: HighPass::new(sample_rate),
+            length,
+            frames: Vec::new(),
+        })
+    }
+    pub fn source(&self) -> AudioSource {
+        self.source
+    }
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+    pub fn is_finished(&self) -> bool {
+        self.frames.len() == self.length
+    }
+    pub fn frames(&self) -> &[StereoFrame] {
+        &self.frames
+    }
+    /// Moves on by a T-cycle. Returns the channels to mix when a frame is
+    /// due.
+    pub(crate) fn tick(&mut self) -> Option<u8> {
+        if self.is_finished() {
+            return None;
+        }
+        self.sample_phase += self.sample_rate;
+        if self.sample_phase < CLOCK_RATE {
+            return None;
+        }
+        self.sample_phase -= CLOCK_RATE;
+        Some(self.source.channels())
+    }
+    pub(crate) fn push(&mut self, mixed: [f64; 2]) {
+        let frame = self.high_pass.filter(mixed);
+        self.frames.push(frame);
+    }
+    /// Writes what has been recorded so far as a 16 bit stereo PCM WAV file.
+    pub fn write_wav(&self, writer: &mut impl Write) -> io::Result<()> {
+        let data_size = self.frames.len() as u32 * WAV_BLOCK_ALIGN as u32;
+        let mut wav = Vec::with_capacity((WAV_HEADER_SIZE + data_size) as usize);
+        wav.extend_from_slice(b"RIFF");
+        wav.extend_from_slice(&(WAV_HEADER_SIZE - 8 + data_size).to_le_bytes());
+        wav.extend_from_slice(b"WAVE");
+        wav.extend_from_slice(b"fmt ");
+        wav.extend_from_slice(&16_u32.to_le_bytes());
+        wav.extend_from_slice(&WAV_FORMAT_PCM.to_le_bytes());
+        wav.extend_from_slice(&WAV_CHANNELS.to_le_bytes());
+        wav.extend_from_slice(&self.sample_rate.to_le_bytes());
+        wav.extend_from_slice(&(self.sample_rate * WAV_BLOCK_ALIGN as u32).to_le_bytes());
+        wav.extend_from_slice(&WAV_BLOCK_ALIGN.to_le_bytes());
+        wav.extend_from_slice(&WAV_BITS_PER_SAMPLE.to_le_bytes());
+        wav.extend_from_slice(b"data");
+        wav.extend_from_slice(&data_size.to_le_bytes());
+        for frame in &self.frames {
+            wav.extend_from_slice(&frame.left.to_le_bytes());
+            wav.extend_from_slice(&frame.right.to_le_bytes());
+        }
+        writer.write_all(&wav)
+    }
+}
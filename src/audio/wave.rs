@@ -0,0 +1,74 @@
+use crate::audio::LengthCounter;
+use crate::savestate::{SaveState, SaveStateError, StateReader, StateWriter};
+
+/// Channel 3, which plays the 32 four bit samples in wave RAM.
+#[derive(Copy, Clone, Default, PartialEq)]
+pub struct Wave {
+    pub enabled: bool,
+    pub length: LengthCounter,
+    /// T-cycles until the next sample is read.
+    timer: u16,
+    position: u8,
+    sample: u8,
+}
+impl Wave {
+    pub const LENGTH: u16 = 256;
+    fn period_cycles(period: u16) -> u16 {
+        (2048 - period) * 2
+    }
+    pub fn dac_enabled(dac_enable: u8) -> bool {
+        dac_enable & 0b1000_0000 != 0
+    }
+    pub fn trigger(&mut self, dac_enable: u8, period: u16) {
+        self.enabled = Self::dac_enabled(dac_enable);
+        self.length.trigger(Self::LENGTH);
+        self.timer = Self::period_cycles(period);
+        self.position = 0;
+    }
+    pub fn tick(&mut self, period: u16, wave_pattern_ram: &[u8; 16]) {
+        self.timer = self.timer.saturating_sub(1);
+        if self.timer == 0 {
+            self.timer = Self::period_cycles(period);
+            self.position = (self.position + 1) % 32;
+            // Each byte holds two samples, the upper nibble first.
+            let byte = wave_pattern_ram[self.position as usize / 2];
+            self.sample = match self.position % 2 {
+                0 => byte >> 4,
+                _ => byte & 0x0F,
+            };
+        }
+    }
+    /// Volume 0-15 the channel is putting out right now.
+    pub fn output(&self, output_level: u8) -> u8 {
+        let shift = match (output_level >> 5) & 0b11 {
+            0b00 => 4,
+            0b01 => 0,
+            0b10 => 1,
+            _ => 2,
+        };
+        match self.enabled {
+            true => self.sample >> shift,
+            false => 0,
+        }
+    }
+}
+impl SaveState for Wave {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_bool(self.enabled);
+        self.length.save_state(writer);
+        writer.write_u16(self.timer);
+        writer.write_u8(self.position);
+        writer.write_u8(self.sample);
+    }
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), SaveStateError> {
+        self.enabled = reader.read_bool()?;
+        self.length.load_state(reader)?;
+        self.timer = reader.read_u16()?;
+        self.position = reader.read_u8()?;
+        if self.position >= 32 {
+            return Err(SaveStateError::Corrupt("wave position out of range"));
+        }
+        self.sample = reader.read_u8()? & 0x0F;
+        Ok(())
+    }
+}
@@ -0,0 +1,863 @@
+use std::{
+    fmt, fs,
+    io::{self, Seek, SeekFrom, Write},
+    ops::Range,
+    path::Path,
+    sync::Arc,
+};
+
+pub use crate::cartridge::rtc::{
+    ManualClock, RTC_FOOTER_SIZE, RTC_FOOTER_SIZE_LEGACY, Rtc, RtcClock, RtcRegisters, SystemClock,
+};
+use crate::cartridge::{
+    mbc1::Mbc1,
+    mbc2::{MBC2_RAM_SIZE, Mbc2},
+    mbc3::Mbc3,
+    mbc5::Mbc5,
+};
+use crate::savestate::{SaveState, SaveStateError, StateReader, StateWriter};
+
+mod mbc1;
+mod mbc2;
+mod mbc3;
+mod mbc5;
+mod rtc;
+
+const HEADER_END: usize = 0x014F;
+const TITLE_START: usize = 0x0134;
+const TITLE_END: usize = 0x0143;
+const MANUFACTURER_START: usize = 0x013F;
+const CGB_FLAG: usize = 0x0143;
+const SGB_FLAG: usize = 0x0146;
+const CARTRIDGE_TYPE: usize = 0x0147;
+const ROM_SIZE: usize = 0x0148;
+const RAM_SIZE: usize = 0x0149;
+const DESTINATION: usize = 0x014A;
+const HEADER_CHECKSUM: usize = 0x014D;
+const GLOBAL_CHECKSUM: usize = 0x014E;
+const LOGO: Range<usize> = 0x0104..0x0134;
+
+pub const ROM_BANK_SIZE: usize = 0x4000;
+pub const RAM_BANK_SIZE: usize = 0x2000;
+
+#[derive(Debug)]
+pub enum CartridgeError {
+    Io(io::Error),
+    TooSmall(usize),
+    SaveSize { expected: usize, found: usize },
+}
+impl fmt::Display for CartridgeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CartridgeError::Io(error) => write!(f, "failed to read cartridge: {error}"),
+            CartridgeError::TooSmall(length) => write!(
+                f,
+                "cartridge image is {length} bytes, too small to contain a header"
+            ),
+            CartridgeError::SaveSize { expected, found } => write!(
+                f,
+                "save file is {found} bytes, expected {expected} bytes for this cartridge"
+            ),
+        }
+    }
+}
+impl std::error::Error for CartridgeError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            CartridgeError::Io(error) => Some(error),
+            CartridgeError::TooSmall(_) | CartridgeError::SaveSize { .. } => None,
+        }
+    }
+}
+impl From<io::Error> for CartridgeError {
+    fn from(error: io::Error) -> Self {
+        CartridgeError::Io(error)
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum CgbSupport {
+    None,
+    Enhanced,
+    Only,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Destination {
+    Japan,
+    Overseas,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum MapperKind {
+    None,
+    Mbc1,
+    Mbc2,
+    Mbc3,
+    Mbc5,
+    Unsupported(u8),
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct CartridgeType {
+    pub code: u8,
+    pub mapper: MapperKind,
+    pub ram: bool,
+    pub battery: bool,
+    pub timer: bool,
+    pub rumble: bool,
+}
+impl CartridgeType {
+    pub fn from_code(code: u8) -> Self {
+        let (mapper, ram, battery, timer, rumble) = match code {
+            0x00 => (MapperKind::None, false, false, false, false),
+            0x01 => (MapperKind::Mbc1, false, false, false, false),
+            0x02 => (MapperKind::Mbc1, true, false, false, false),
+            0x03 => (MapperKind::Mbc1, true, true, false, false),
+            0x05 => (MapperKind::Mbc2, false, false, false, false),
+            0x06 => (MapperKind::Mbc2, false, true, false, false),
+            0x08 => (MapperKind::None, true, false, false, false),
+            0x09 => (MapperKind::None, true, true, false, false),
+            0x0F => (MapperKind::Mbc3, false, true, true, false),
+            0x10 => (MapperKind::Mbc3, true, true, true, false),
+            0x11 => (MapperKind::Mbc3, false, false, false, false),
+            0x12 => (MapperKind::Mbc3, true, false, false, false),
+            0x13 => (MapperKind::Mbc3, true, true, false, false),
+            0x19 => (MapperKind::Mbc5, false, false, false, false),
+            0x1A => (MapperKind::Mbc5, true, false, false, false),
+            0x1B => (MapperKind::Mbc5, true, true, false, false),
+            0x1C => (MapperKind::Mbc5, false, false, false, true),
+            0x1D => (MapperKind::Mbc5, true, false, false, true),
+            0x1E => (MapperKind::Mbc5, true, true, false, true),
+            _ => (MapperKind::Unsupported(code), false, false, false, false),
+        };
+        Self {
+            code,
+            mapper,
+            ram,
+            battery,
+            timer,
+            rumble,
+        }
+    }
+}
+
+/// The cartridge header found at 0x0100-0x014F of every ROM image.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CartridgeHeader {
+    pub title: String,
+    pub cgb_support: CgbSupport,
+    pub sgb_support: bool,
+    pub cartridge_type: CartridgeType,
+    pub rom_size_code: u8,
+    pub ram_size_code: u8,
+    pub destination: Destination,
+    pub header_checksum: u8,
+    pub global_checksum: u16,
+}
+impl CartridgeHeader {
+    pub fn parse(rom: &[u8]) -> Result<Self, CartridgeError> {
+        if rom.len() <= HEADER_END {
+            return Err(CartridgeError::TooSmall(rom.len()));
+        }
+        let cgb_support = match rom[CGB_FLAG] {
+            0x80 => CgbSupport::Enhanced,
+            0xC0 => CgbSupport::Only,
+            _ => CgbSupport::None,
+        };
+        // CGB aware titles give up the last bytes of the title to the
+        // manufacturer code and the CGB flag.
+        let title_end = match cgb_support {
+            CgbSupport::None => TITLE_END + 1,
+            _ => MANUFACTURER_START,
+        };
+        let title = rom[TITLE_START..title_end]
+            .iter()
+            .take_while(|byte| **byte != 0x00)
+            .map(|byte| match byte.is_ascii_graphic() || *byte == b' ' {
+                true => *byte as char,
+                false => '?',
+            })
+            .collect::<String>()
+            .trim_end()
+            .to_string();
+        Ok(Self {
+            title,
+            cgb_support,
+            sgb_support: rom[SGB_FLAG] == 0x03,
+            cartridge_type: CartridgeType::from_code(rom[CARTRIDGE_TYPE]),
+            rom_size_code: rom[ROM_SIZE],
+            ram_size_code: rom[RAM_SIZE],
+            destination: match rom[DESTINATION] {
+                0x00 => Destination::Japan,
+                _ => Destination::Overseas,
+            },
+            header_checksum: rom[HEADER_CHECKSUM],
+            global_checksum: u16::from_be_bytes([rom[GLOBAL_CHECKSUM], rom[GLOBAL_CHECKSUM + 1]]),
+        })
+    }
+    /// ROM size in bytes as declared by the header.
+    pub fn rom_size(&self) -> usize {
+        match self.rom_size_code {
+            code @ 0x00..=0x08 => (ROM_BANK_SIZE * 2) << code,
+            _ => ROM_BANK_SIZE * 2,
+        }
+    }
+    pub fn rom_banks(&self) -> usize {
+        self.rom_size() / ROM_BANK_SIZE
+    }
+    /// External RAM size in bytes as declared by the header.
+    pub fn ram_size(&self) -> usize {
+        match self.ram_size_code {
+            0x01 => 0x0800,
+            0x02 => RAM_BANK_SIZE,
+            0x03 => RAM_BANK_SIZE * 4,
+            0x04 => RAM_BANK_SIZE * 16,
+            0x05 => RAM_BANK_SIZE * 8,
+            _ => 0,
+        }
+    }
+}
+
+/// Destination for battery backed saves. Anything seekable is rewound and
+/// overwritten with the whole save on every flush.
+pub trait SaveWriter: Send {
+    fn write_save(&mut self, save: &[u8]) -> io::Result<()>;
+}
+impl<W: Write + Seek + Send> SaveWriter for W {
+    fn write_save(&mut self, save: &[u8]) -> io::Result<()> {
+        self.seek(SeekFrom::Start(0))?;
+        self.write_all(save)?;
+        self.flush()
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Mapper {
+    None,
+    Mbc1(Mbc1),
+    Mbc2(Mbc2),
+    Mbc3(Mbc3),
+    Mbc5(Mbc5),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Cartridge {
+    header: CartridgeHeader,
+    rom: Vec<u8>,
+    rom_mask: usize,
+    ram: Vec<u8>,
+    /// Set when RAM or the clock changes, cleared once the save is written
+    /// out by `GameBoy::flush_save` or replaced by an import.
+    save_dirty: bool,
+    mapper: Mapper,
+}
+impl Cartridge {
+    pub fn from_bytes(rom: Vec<u8>) -> Result<Self, CartridgeError> {
+        let header = CartridgeHeader::parse(&rom)?;
+        let rom_banks = rom.len().div_ceil(ROM_BANK_SIZE).next_power_of_two().max(2);
+        let mapper = match header.cartridge_type.mapper {
+            MapperKind::Mbc1 => Mapper::Mbc1(Mbc1::new(Self::is_mbc1_multicart(&rom))),
+            MapperKind::Mbc2 => Mapper::Mbc2(Mbc2::new()),
+            MapperKind::Mbc3 => Mapper::Mbc3(Mbc3::new(header.cartridge_type.timer.then(Rtc::new))),
+            MapperKind::Mbc5 => Mapper::Mbc5(Mbc5::new(header.cartridge_type.rumble)),
+            _ => Mapper::None,
+        };
+        let ram_size = match mapper {
+            Mapper::Mbc2(_) => MBC2_RAM_SIZE,
+            _ => header.ram_size(),
+        };
+        Ok(Self {
+            ram: vec![0; ram_size],
+            header,
+            rom,
+            rom_mask: rom_banks * ROM_BANK_SIZE - 1,
+            save_dirty: false,
+            mapper,
+        })
+    }
+    /// MBC1M multicarts are 1 MiB images made of four 256 KiB games, each
+    /// with its own copy of the boot logo at the start of its first bank.
+    fn is_mbc1_multicart(rom: &[u8]) -> bool {
+        const SECOND_GAME: usize = 0x10 * ROM_BANK_SIZE;
+        rom.len() == 0x40 * ROM_BANK_SIZE
+            && rom[SECOND_GAME + LOGO.start..SECOND_GAME + LOGO.end] == rom[LOGO]
+    }
+    pub fn from_path(path: impl AsRef<Path>) -> Result<Self, CartridgeError> {
+        Self::from_bytes(fs::read(path)?)
+    }
+    pub fn header(&self) -> &CartridgeHeader {
+        &self.header
+    }
+    pub fn rom(&self) -> &[u8] {
+        &self.rom
+    }
+    /// Checksum over 0x0134-0x014C, verified by the boot ROM before it hands
+    /// over control to the cartridge.
+    pub fn computed_header_checksum(&self) -> u8 {
+        self.rom[TITLE_START..HEADER_CHECKSUM]
+            .iter()
+            .fold(0_u8, |checksum, byte| {
+                checksum.wrapping_sub(*byte).wrapping_sub(1)
+            })
+    }
+    pub fn header_checksum_valid(&self) -> bool {
+        self.computed_header_checksum() == self.header.header_checksum
+    }
+    /// Sum of every byte in the image apart from the global checksum itself.
+    /// Real hardware never checks this.
+    pub fn computed_global_checksum(&self) -> u16 {
+        self.rom
+            .iter()
+            .enumerate()
+            .filter(|(address, _)| !(GLOBAL_CHECKSUM..=GLOBAL_CHECKSUM + 1).contains(address))
+            .fold(0_u16, |checksum, (_, byte)| {
+                checksum.wrapping_add(*byte as u16)
+            })
+    }
+    pub fn global_checksum_valid(&self) -> bool {
+        self.computed_global_checksum() == self.header.global_checksum
+    }
+    /// Reads a byte of the image, treating anything past the end as open bus.
+    pub fn rom_byte(&self, offset: usize) -> u8 {
+        self.rom.get(offset).copied().unwrap_or(0xFF)
+    }
+    pub fn ram(&self) -> &[u8] {
+        &self.ram
+    }
+    pub fn rtc(&self) -> Option<&Rtc> {
+        match &self.mapper {
+            Mapper::Mbc3(mbc) => mbc.rtc(),
+            _ => None,
+        }
+    }
+    /// Whether an MBC5 rumble cartridge currently has its motor switched on.
+    pub fn rumble_active(&self) -> bool {
+        match &self.mapper {
+            Mapper::Mbc5(mbc) => mbc.rumble_active(),
+            _ => false,
+        }
+    }
+    /// Runs the MBC3 real time clock on a host clock instead of emulated
+    /// time, if the cartridge has one.
+    pub fn set_rtc_clock(&mut self, clock: Arc<dyn RtcClock>) {
+        if let Mapper::Mbc3(mbc) = &mut self.mapper {
+            mbc.set_rtc_clock(clock);
+        }
+    }
+    /// Moves the real time clock on by `cycles` T-cycles of emulated time.
+    pub fn tick_rtc(&mut self, cycles: u32) {
+        if let Mapper::Mbc3(mbc) = &mut self.mapper
+            && let Some(rtc) = mbc.rtc_mut()
+        {
+            rtc.tick(cycles);
+        }
+    }
+    fn ram_offset(&self, address: u16) -> Option<usize> {
+        let offset = match &self.mapper {
+            Mapper::None => Some((address - 0xA000) as usize),
+            Mapper::Mbc1(mbc) => mbc.ram_offset(address),
+            Mapper::Mbc2(mbc) => mbc.ram_offset(address),
+            Mapper::Mbc3(mbc) => mbc.ram_offset(address),
+            Mapper::Mbc5(mbc) => mbc.ram_offset(address),
+        }?;
+        match self.ram.len() {
+            0 => None,
+            length => Some(offset % length),
+        }
+    }
+    /// Reads from 0x0000-0x7FFF through the mapper.
+    pub fn read_rom(&self, address: u16) -> u8 {
+        let offset = match &self.mapper {
+            Mapper::None => address as usize,
+            Mapper::Mbc1(mbc) => mbc.rom_offset(address),
+            Mapper::Mbc2(mbc) => mbc.rom_offset(address),
+            Mapper::Mbc3(mbc) => mbc.rom_offset(address),
+            Mapper::Mbc5(mbc) => mbc.rom_offset(address),
+        };
+        self.rom_byte(offset & self.rom_mask)
+    }
+    /// Writes to 0x0000-0x7FFF never reach the ROM, they set mapper registers.
+    pub fn write_rom(&mut self, address: u16, value: u8) {
+        match &mut self.mapper {
+            Mapper::None => (),
+            Mapper::Mbc1(mbc) => mbc.write_register(address, value),
+            Mapper::Mbc2(mbc) => mbc.write_register(address, value),
+            Mapper::Mbc3(mbc) => mbc.write_register(address, value),
+            Mapper::Mbc5(mbc) => mbc.write_register(address, value),
+        }
+    }
+    /// Reads from external RAM at 0xA000-0xBFFF.
+    pub fn read_ram(&self, address: u16) -> u8 {
+        if let Mapper::Mbc3(mbc) = &self.mapper
+            && mbc.rtc_selected()
+        {
+            return mbc.read_rtc();
+        }
+        match (self.ram_offset(address), &self.mapper) {
+            // Only the low nibble of each MBC2 cell exists.
+            (Some(offset), Mapper::Mbc2(_)) => self.ram[offset] | 0xF0,
+            (Some(offset), _) => self.ram[offset],
+            (None, _) => 0xFF,
+        }
+    }
+    pub fn write_ram(&mut self, address: u16, value: u8) {
+        if let Mapper::Mbc3(mbc) = &mut self.mapper
+            && mbc.rtc_selected()
+        {
+            self.save_dirty = true;
+            return mbc.write_rtc(value);
+        }
+        if let Some(offset) = self.ram_offset(address) {
+            self.save_dirty |= self.ram[offset] != value;
+            self.ram[offset] = value;
+        }
+    }
+    pub fn has_battery(&self) -> bool {
+        self.header.cartridge_type.battery
+    }
+    /// Whether RAM or the clock changed since the save was last flushed.
+    pub fn save_dirty(&self) -> bool {
+        self.save_dirty
+    }
+    pub(crate) fn mark_saved(&mut self) {
+        self.save_dirty = false;
+    }
+    /// External RAM followed by the RTC footer on cartridges with a clock,
+    /// the `.sav` layout shared by most emulators.
+    pub fn export_save(&self) -> Vec<u8> {
+        let mut save = self.ram.clone();
+        if let Some(rtc) = self.rtc() {
+            save.extend_from_slice(&rtc.footer());
+        }
+        save
+    }
+    pub fn import_save(&mut self, save: &[u8]) -> Result<(), CartridgeError> {
+        let ram_size = self.ram.len();
+        let footer_size = save.len().saturating_sub(ram_size);
+        let has_rtc = self.rtc().is_some();
+        let valid = match has_rtc {
+            true => [0, RTC_FOOTER_SIZE, RTC_FOOTER_SIZE_LEGACY].contains(&footer_size),
+            false => footer_size == 0,
+        };
+        if save.len() < ram_size || !valid {
+            return Err(CartridgeError::SaveSize {
+                expected: ram_size + has_rtc as usize * RTC_FOOTER_SIZE,
+                found: save.len(),
+            });
+        }
+        self.ram.copy_from_slice(&save[..ram_size]);
+        if let Mapper::Mbc3(mbc) = &mut self.mapper
+            && let Some(rtc) = mbc.rtc_mut()
+            && footer_size != 0
+        {
+            rtc.load_footer(&save[ram_size..]);
+        }
+        self.save_dirty = false;
+        Ok(())
+    }
+}
+
+impl SaveState for Cartridge {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_u8(self.header.header_checksum);
+        writer.write_u16(self.header.global_checksum);
+        writer.write_u32(self.ram.len() as u32);
+        writer.write_bytes(&self.ram);
+        writer.write_bool(self.save_dirty);
+        match &self.mapper {
+            Mapper::None => writer.write_u8(0x00),
+            Mapper::Mbc1(mbc) => {
+                writer.write_u8(0x01);
+                mbc.save_state(writer);
+            }
+            Mapper::Mbc2(mbc) => {
+                writer.write_u8(0x02);
+                mbc.save_state(writer);
+            }
+            Mapper::Mbc3(mbc) => {
+                writer.write_u8(0x03);
+                mbc.save_state(writer);
+            }
+            Mapper::Mbc5(mbc) => {
+                writer.write_u8(0x05);
+                mbc.save_state(writer);
+            }
+        }
+    }
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), SaveStateError> {
+        if reader.read_u8()? != self.header.header_checksum
+            || reader.read_u16()? != self.header.global_checksum
+            || reader.read_u32()? as usize != self.ram.len()
+        {
+            return Err(SaveStateError::CartridgeMismatch);
+        }
+        reader.read_bytes(&mut self.ram)?;
+        self.save_dirty = reader.read_bool()?;
+        match (reader.read_u8()?, &mut self.mapper) {
+            (0x00, Mapper::None) => Ok(()),
+            (0x01, Mapper::Mbc1(mbc)) => mbc.load_state(reader),
+            (0x02, Mapper::Mbc2(mbc)) => mbc.load_state(reader),
+            (0x03, Mapper::Mbc3(mbc)) => mbc.load_state(reader),
+            (0x05, Mapper::Mbc5(mbc)) => mbc.load_state(reader),
+            _ => Err(SaveStateError::CartridgeMismatch),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn build_rom(title: &[u8], cartridge_type: u8, rom_size: u8, ram_size: u8) -> Vec<u8> {
+        let mut rom = vec![0_u8; (ROM_BANK_SIZE * 2) << rom_size];
+        rom[TITLE_START..TITLE_START + title.len()].copy_from_slice(title);
+        rom[CARTRIDGE_TYPE] = cartridge_type;
+        rom[ROM_SIZE] = rom_size;
+        rom[RAM_SIZE] = ram_size;
+        rom[DESTINATION] = 0x01;
+        let checksum = rom[TITLE_START..HEADER_CHECKSUM]
+            .iter()
+            .fold(0_u8, |checksum, byte| {
+                checksum.wrapping_sub(*byte).wrapping_sub(1)
+            });
+        rom[HEADER_CHECKSUM] = checksum;
+        let global = rom
+            .iter()
+            .fold(0_u16, |sum, byte| sum.wrapping_add(*byte as u16));
+        rom[GLOBAL_CHECKSUM..GLOBAL_CHECKSUM + 2].copy_from_slice(&global.to_be_bytes());
+        rom
+    }
+
+    #[test]
+    fn test_parse_header() {
+        let cartridge = Cartridge::from_bytes(build_rom(b"CRABBOY", 0x03, 0x02, 0x03)).unwrap();
+        let header = cartridge.header();
+        assert_eq!(header.title, "CRABBOY");
+        assert_eq!(header.cgb_support, CgbSupport::None);
+        assert!(!header.sgb_support);
+        assert_eq!(header.cartridge_type.mapper, MapperKind::Mbc1);
+        assert!(header.cartridge_type.ram && header.cartridge_type.battery);
+        assert_eq!(header.rom_size(), 128 * 1024);
+        assert_eq!(header.rom_banks(), 8);
+        assert_eq!(header.ram_size(), 32 * 1024);
+        assert_eq!(header.destination, Destination::Overseas);
+        assert!(cartridge.header_checksum_valid());
+        assert!(cartridge.global_checksum_valid());
+    }
+
+    #[test]
+    fn test_cgb_title_excludes_manufacturer_code() {
+        let mut rom = build_rom(b"POKEMON_GLDAAUE", 0x10, 0x00, 0x00);
+        rom[CGB_FLAG] = 0x80;
+        let cartridge = Cartridge::from_bytes(rom).unwrap();
+        assert_eq!(cartridge.header().title, "POKEMON_GLD");
+        assert_eq!(cartridge.header().cgb_support, CgbSupport::Enhanced);
+        assert!(!cartridge.header_checksum_valid());
+    }
+
+    /// Tags every ROM bank with its own number so banking can be observed.
+    fn build_banked_rom(cartridge_type: u8, rom_size: u8, ram_size: u8) -> Vec<u8> {
+        let mut rom = build_rom(b"BANKS", cartridge_type, rom_size, ram_size);
+        for bank in 0..rom.len() / ROM_BANK_SIZE {
+            rom[bank * ROM_BANK_SIZE + 0x2000] = bank as u8;
+        }
+        rom
+    }
+
+    #[test]
+    fn test_mbc1_rom_banking() {
+        let mut cartridge = Cartridge::from_bytes(build_banked_rom(0x01, 0x06, 0x00)).unwrap();
+        assert_eq!(cartridge.read_rom(0x2000), 0x00);
+        assert_eq!(cartridge.read_rom(0x6000), 0x01);
+        cartridge.write_rom(0x2000, 0x00);
+        assert_eq!(cartridge.read_rom(0x6000), 0x01);
+        cartridge.write_rom(0x2000, 0x05);
+        assert_eq!(cartridge.read_rom(0x6000), 0x05);
+        cartridge.write_rom(0x4000, 0x03);
+        cartridge.write_rom(0x3FFF, 0x1F);
+        assert_eq!(cartridge.read_rom(0x6000), 0x7F);
+        // Bank 0x20 can't be selected in the switchable region, the zero
+        // check on the lower five bits turns it into 0x21.
+        cartridge.write_rom(0x4000, 0x01);
+        cartridge.write_rom(0x2000, 0x20);
+        assert_eq!(cartridge.read_rom(0x6000), 0x21);
+        assert_eq!(cartridge.read_rom(0x2000), 0x00);
+        cartridge.write_rom(0x6000, 0x01);
+        assert_eq!(cartridge.read_rom(0x2000), 0x20);
+    }
+
+    #[test]
+    fn test_mbc1_bank_wraps_to_rom_size() {
+        let mut cartridge = Cartridge::from_bytes(build_banked_rom(0x01, 0x02, 0x00)).unwrap();
+        cartridge.write_rom(0x2000, 0x0B);
+        assert_eq!(cartridge.read_rom(0x6000), 0x03);
+    }
+
+    #[test]
+    fn test_mbc1_ram_banking() {
+        let mut cartridge = Cartridge::from_bytes(build_banked_rom(0x03, 0x02, 0x03)).unwrap();
+        cartridge.write_ram(0xA000, 0x42);
+        assert_eq!(cartridge.read_ram(0xA000), 0xFF);
+        cartridge.write_rom(0x0000, 0x0A);
+        cartridge.write_ram(0xA000, 0x42);
+        assert_eq!(cartridge.read_ram(0xA000), 0x42);
+        // RAM banks only switch in the advanced banking mode.
+        cartridge.write_rom(0x4000, 0x02);
+        assert_eq!(cartridge.read_ram(0xA000), 0x42);
+        cartridge.write_rom(0x6000, 0x01);
+        assert_eq!(cartridge.read_ram(0xA000), 0x00);
+        cartridge.write_ram(0xBFFF, 0x24);
+        assert_eq!(cartridge.ram()[2 * RAM_BANK_SIZE + 0x1FFF], 0x24);
+        cartridge.write_rom(0x0000, 0x00);
+        assert_eq!(cartridge.read_ram(0xBFFF), 0xFF);
+    }
+
+    #[test]
+    fn test_mbc1_multicart() {
+        let mut rom = build_banked_rom(0x01, 0x05, 0x00);
+        for game in 0..4 {
+            let bank = game * 0x10 * ROM_BANK_SIZE;
+            rom[bank + LOGO.start..bank + LOGO.end].copy_from_slice(&[0xCE; 0x30]);
+        }
+        let mut cartridge = Cartridge::from_bytes(rom).unwrap();
+        cartridge.write_rom(0x4000, 0x01);
+        cartridge.write_rom(0x2000, 0x12);
+        assert_eq!(cartridge.read_rom(0x6000), 0x12);
+        cartridge.write_rom(0x6000, 0x01);
+        assert_eq!(cartridge.read_rom(0x2000), 0x10);
+    }
+
+    #[test]
+    fn test_mbc3_rom_and_ram_banking() {
+        let mut cartridge = Cartridge::from_bytes(build_banked_rom(0x13, 0x06, 0x03)).unwrap();
+        cartridge.write_rom(0x2000, 0x00);
+        assert_eq!(cartridge.read_rom(0x6000), 0x01);
+        cartridge.write_rom(0x2000, 0x7F);
+        assert_eq!(cartridge.read_rom(0x6000), 0x7F);
+        cartridge.write_rom(0x0000, 0x0A);
+        cartridge.write_rom(0x4000, 0x03);
+        cartridge.write_ram(0xA123, 0x99);
+        assert_eq!(cartridge.ram()[3 * RAM_BANK_SIZE + 0x123], 0x99);
+        // No clock on this cartridge, so the RTC selects read as open bus.
+        cartridge.write_rom(0x4000, 0x08);
+        assert_eq!(cartridge.read_ram(0xA000), 0xFF);
+    }
+
+    fn rtc_cartridge() -> (Cartridge, Arc<ManualClock>) {
+        let clock = Arc::new(ManualClock::new());
+        let mut cartridge = Cartridge::from_bytes(build_banked_rom(0x10, 0x02, 0x03)).unwrap();
+        cartridge.set_rtc_clock(clock.clone());
+        cartridge.write_rom(0x0000, 0x0A);
+        (cartridge, clock)
+    }
+
+    fn latch_and_read(cartridge: &mut Cartridge, register: u8) -> u8 {
+        cartridge.write_rom(0x6000, 0x00);
+        cartridge.write_rom(0x6000, 0x01);
+        cartridge.write_rom(0x4000, register);
+        cartridge.read_ram(0xA000)
+    }
+
+    #[test]
+    fn test_mbc3_rtc_counts_host_clock() {
+        let (mut cartridge, clock) = rtc_cartridge();
+        clock.advance(Duration::from_secs(59));
+        assert_eq!(latch_and_read(&mut cartridge, 0x08), 59);
+        clock.advance(Duration::from_millis(1500));
+        assert_eq!(latch_and_read(&mut cartridge, 0x08), 0);
+        assert_eq!(latch_and_read(&mut cartridge, 0x09), 1);
+        clock.advance(Duration::from_millis(500));
+        assert_eq!(latch_and_read(&mut cartridge, 0x08), 1);
+        clock.advance(Duration::from_secs(2 * 86_400 + 3 * 3600));
+        assert_eq!(latch_and_read(&mut cartridge, 0x0A), 3);
+        assert_eq!(latch_and_read(&mut cartridge, 0x0B), 2);
+    }
+
+    #[test]
+    fn test_mbc3_rtc_ticks_with_cycles() {
+        const CYCLES_PER_SECOND: u32 = 4_194_304;
+        let mut cartridge = Cartridge::from_bytes(build_banked_rom(0x10, 0x02, 0x03)).unwrap();
+        cartridge.write_rom(0x0000, 0x0A);
+        cartridge.tick_rtc(CYCLES_PER_SECOND * 59);
+        assert_eq!(latch_and_read(&mut cartridge, 0x08), 59);
+        cartridge.tick_rtc(CYCLES_PER_SECOND / 2);
+        assert_eq!(latch_and_read(&mut cartridge, 0x08), 59);
+        cartridge.tick_rtc(CYCLES_PER_SECOND / 2);
+        assert_eq!(latch_and_read(&mut cartridge, 0x08), 0);
+        assert_eq!(latch_and_read(&mut cartridge, 0x09), 1);
+    }
+
+    #[test]
+    fn test_mbc3_rtc_latch_holds_value() {
+        let (mut cartridge, clock) = rtc_cartridge();
+        clock.advance(Duration::from_secs(10));
+        assert_eq!(latch_and_read(&mut cartridge, 0x08), 10);
+        clock.advance(Duration::from_secs(10));
+        assert_eq!(cartridge.read_ram(0xA000), 10);
+        // Only a 0x00 -> 0x01 sequence latches.
+        cartridge.write_rom(0x6000, 0x01);
+        assert_eq!(cartridge.read_ram(0xA000), 10);
+        assert_eq!(latch_and_read(&mut cartridge, 0x08), 20);
+    }
+
+    #[test]
+    fn test_mbc3_rtc_day_carry_and_halt() {
+        let (mut cartridge, clock) = rtc_cartridge();
+        cartridge.write_rom(0x4000, 0x0B);
+        cartridge.write_ram(0xA000, 0xFF);
+        cartridge.write_rom(0x4000, 0x0C);
+        cartridge.write_ram(0xA000, 0x01);
+        clock.advance(Duration::from_secs(86_400));
+        assert_eq!(latch_and_read(&mut cartridge, 0x0B), 0x00);
+        assert_eq!(latch_and_read(&mut cartridge, 0x0C), 0x80);
+        cartridge.write_rom(0x4000, 0x0C);
+        cartridge.write_ram(0xA000, 0x40);
+        clock.advance(Duration::from_secs(3600));
+        assert_eq!(latch_and_read(&mut cartridge, 0x0A), 0);
+        cartridge.write_rom(0x4000, 0x0C);
+        cartridge.write_ram(0xA000, 0x00);
+        clock.advance(Duration::from_secs(3600));
+        assert_eq!(latch_and_read(&mut cartridge, 0x0A), 1);
+    }
+
+    #[test]
+    fn test_mbc3_rtc_out_of_range_values_wrap() {
+        let (mut cartridge, clock) = rtc_cartridge();
+        cartridge.write_rom(0x4000, 0x08);
+        cartridge.write_ram(0xA000, 62);
+        clock.advance(Duration::from_secs(3));
+        assert_eq!(latch_and_read(&mut cartridge, 0x08), 1);
+        assert_eq!(latch_and_read(&mut cartridge, 0x09), 0);
+    }
+
+    #[test]
+    fn test_mbc5_rom_banking() {
+        let mut rom = build_banked_rom(0x19, 0x08, 0x00);
+        rom[0x100 * ROM_BANK_SIZE + 0x2000] = 0xAB;
+        let mut cartridge = Cartridge::from_bytes(rom).unwrap();
+        assert_eq!(cartridge.read_rom(0x6000), 0x01);
+        // MBC5 can map bank 0 into the switchable region.
+        cartridge.write_rom(0x2000, 0x00);
+        assert_eq!(cartridge.read_rom(0x6000), 0x00);
+        cartridge.write_rom(0x2000, 0xFF);
+        assert_eq!(cartridge.read_rom(0x6000), 0xFF);
+        cartridge.write_rom(0x2000, 0x00);
+        cartridge.write_rom(0x3000, 0x01);
+        assert_eq!(cartridge.read_rom(0x6000), 0xAB);
+    }
+
+    #[test]
+    fn test_mbc5_ram_banking_and_rumble() {
+        let mut cartridge = Cartridge::from_bytes(build_banked_rom(0x1B, 0x02, 0x04)).unwrap();
+        cartridge.write_rom(0x0000, 0x0A);
+        cartridge.write_rom(0x4000, 0x0F);
+        cartridge.write_ram(0xA000, 0x5A);
+        assert_eq!(cartridge.ram()[15 * RAM_BANK_SIZE], 0x5A);
+        assert!(!cartridge.rumble_active());
+
+        let mut cartridge = Cartridge::from_bytes(build_banked_rom(0x1E, 0x02, 0x03)).unwrap();
+        cartridge.write_rom(0x0000, 0x0A);
+        cartridge.write_rom(0x4000, 0x0A);
+        assert!(cartridge.rumble_active());
+        cartridge.write_ram(0xA000, 0x5A);
+        assert_eq!(cartridge.ram()[2 * RAM_BANK_SIZE], 0x5A);
+        cartridge.write_rom(0x4000, 0x02);
+        assert!(!cartridge.rumble_active());
+    }
+
+    #[test]
+    fn test_mbc2_registers_decode_a8() {
+        let mut cartridge = Cartridge::from_bytes(build_banked_rom(0x06, 0x03, 0x00)).unwrap();
+        cartridge.write_rom(0x0100, 0x0A);
+        assert_eq!(cartridge.read_rom(0x6000), 0x0A);
+        cartridge.write_rom(0x2000, 0x05);
+        assert_eq!(cartridge.read_rom(0x6000), 0x0A);
+        cartridge.write_rom(0x3F00, 0x00);
+        assert_eq!(cartridge.read_rom(0x6000), 0x01);
+        assert_eq!(cartridge.read_ram(0xA000), 0xFF);
+        cartridge.write_rom(0x0000, 0x0A);
+        assert_eq!(cartridge.read_ram(0xA000), 0xF0);
+    }
+
+    #[test]
+    fn test_mbc2_half_byte_ram() {
+        let mut cartridge = Cartridge::from_bytes(build_banked_rom(0x06, 0x03, 0x00)).unwrap();
+        assert_eq!(cartridge.ram().len(), 0x200);
+        cartridge.write_rom(0x0000, 0x0A);
+        cartridge.write_ram(0xA001, 0x3C);
+        assert_eq!(cartridge.read_ram(0xA001), 0xFC);
+        assert_eq!(cartridge.read_ram(0xA201), 0xFC);
+        assert_eq!(cartridge.read_ram(0xBE01), 0xFC);
+    }
+
+    #[test]
+    fn test_save_round_trip() {
+        let mut cartridge = Cartridge::from_bytes(build_banked_rom(0x03, 0x02, 0x03)).unwrap();
+        assert!(cartridge.has_battery());
+        cartridge.write_rom(0x0000, 0x0A);
+        cartridge.write_ram(0xA000, 0x12);
+        assert!(cartridge.save_dirty());
+        let save = cartridge.export_save();
+        // Only a flush marks the save as written.
+        assert!(cartridge.save_dirty());
+        assert_eq!(save.len(), 4 * RAM_BANK_SIZE);
+
+        let mut restored = Cartridge::from_bytes(build_banked_rom(0x03, 0x02, 0x03)).unwrap();
+        restored.import_save(&save).unwrap();
+        assert_eq!(restored.ram(), cartridge.ram());
+        assert!(matches!(
+            restored.import_save(&save[1..]),
+            Err(CartridgeError::SaveSize {
+                expected: 0x8000,
+                found: 0x7FFF
+            })
+        ));
+    }
+
+    #[test]
+    fn test_save_rtc_footer() {
+        let (mut cartridge, clock) = rtc_cartridge();
+        clock.advance(Duration::from_secs(1_000_000));
+        cartridge.write_rom(0x4000, 0x00);
+        cartridge.write_ram(0xA000, 0x34);
+        assert_eq!(latch_and_read(&mut cartridge, 0x0B), 11);
+        let save = cartridge.export_save();
+        assert_eq!(save.len(), 4 * RAM_BANK_SIZE + RTC_FOOTER_SIZE);
+        let footer = &save[4 * RAM_BANK_SIZE..];
+        // 1,000,000 seconds is 11 days, 13:46:40.
+        assert_eq!(
+            &footer[0..20],
+            &[
+                40, 0, 0, 0, 46, 0, 0, 0, 13, 0, 0, 0, 11, 0, 0, 0, 0, 0, 0, 0
+            ]
+        );
+        assert_eq!(footer[32], 11);
+        assert_eq!(
+            u64::from_le_bytes(footer[40..48].try_into().unwrap()),
+            1_000_000
+        );
+
+        let (mut restored, clock) = rtc_cartridge();
+        clock.advance(Duration::from_secs(1_000_000 + 20));
+        restored.import_save(&save).unwrap();
+        restored.write_rom(0x4000, 0x00);
+        assert_eq!(restored.read_ram(0xA000), 0x34);
+        assert_eq!(latch_and_read(&mut restored, 0x08), 0);
+        assert_eq!(latch_and_read(&mut restored, 0x09), 47);
+
+        let (mut legacy, _) = rtc_cartridge();
+        legacy
+            .import_save(&save[..4 * RAM_BANK_SIZE + RTC_FOOTER_SIZE_LEGACY])
+            .unwrap();
+    }
+
+    #[test]
+    fn test_too_small() {
+        assert!(matches!(
+            Cartridge::from_bytes(vec![0; 0x100]),
+            Err(CartridgeError::TooSmall(0x100))
+        ));
+    }
+}
@@ -0,0 +1,89 @@
+use crate::cartridge::{RAM_BANK_SIZE, ROM_BANK_SIZE};
+use crate::savestate::{SaveState, SaveStateError, StateReader, StateWriter};
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Mbc1 {
+    ram_enabled: bool,
+    /// Lower ROM bank bits written to 0x2000-0x3FFF.
+    bank1: u8,
+    /// Upper ROM bank bits or RAM bank written to 0x4000-0x5FFF.
+    bank2: u8,
+    /// Banking mode written to 0x6000-0x7FFF.
+    advanced_banking: bool,
+    /// MBC1M multicarts only wire four bits of `bank1` to the ROM.
+    multicart: bool,
+}
+impl Mbc1 {
+    pub fn new(multicart: bool) -> Self {
+        Self {
+            ram_enabled: false,
+            bank1: 0x01,
+            bank2: 0x00,
+            advanced_banking: false,
+            multicart,
+        }
+    }
+    pub fn write_register(&mut self, address: u16, value: u8) {
+        match address {
+            0x0000..=0x1FFF => self.ram_enabled = value & 0x0F == 0x0A,
+            0x2000..=0x3FFF => {
+                self.bank1 = match value & 0x1F {
+                    0x00 => 0x01,
+                    bank => bank,
+                }
+            }
+            0x4000..=0x5FFF => self.bank2 = value & 0x03,
+            0x6000..=0x7FFF => self.advanced_banking = value & 0x01 == 0x01,
+            _ => unreachable!(),
+        }
+    }
+    fn bank2_shift(&self) -> u8 {
+        match self.multicart {
+            true => 4,
+            false => 5,
+        }
+    }
+    pub fn rom_offset(&self, address: u16) -> usize {
+        let bank = match address {
+            0x0000..=0x3FFF => match self.advanced_banking {
+                true => self.bank2 << self.bank2_shift(),
+                false => 0,
+            },
+            0x4000..=0x7FFF => {
+                let bank1 = match self.multicart {
+                    true => self.bank1 & 0x0F,
+                    false => self.bank1,
+                };
+                (self.bank2 << self.bank2_shift()) | bank1
+            }
+            _ => unreachable!(),
+        };
+        bank as usize * ROM_BANK_SIZE + (address as usize & (ROM_BANK_SIZE - 1))
+    }
+    pub fn ram_offset(&self, address: u16) -> Option<usize> {
+        if !self.ram_enabled {
+            return None;
+        }
+        let bank = match self.advanced_banking {
+            true => self.bank2,
+            false => 0,
+        };
+        Some(bank as usize * RAM_BANK_SIZE + (address - 0xA000) as usize)
+    }
+}
+
+impl SaveState for Mbc1 {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_bool(self.ram_enabled);
+        writer.write_u8(self.bank1);
+        writer.write_u8(self.bank2);
+        writer.write_bool(self.advanced_banking);
+    }
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), SaveStateError> {
+        self.ram_enabled = reader.read_bool()?;
+        self.bank1 = reader.read_u8()? & 0x1F;
+        self.bank2 = reader.read_u8()? & 0x03;
+        self.advanced_banking = reader.read_bool()?;
+        Ok(())
+    }
+}
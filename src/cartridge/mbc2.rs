@@ -0,0 +1,61 @@
+use crate::cartridge::ROM_BANK_SIZE;
+use crate::savestate::{SaveState, SaveStateError, StateReader, StateWriter};
+
+/// MBC2 carries 512 half-byte cells of RAM inside the controller itself.
+pub const MBC2_RAM_SIZE: usize = 0x0200;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Mbc2 {
+    ram_enabled: bool,
+    rom_bank: u8,
+}
+impl Mbc2 {
+    pub fn new() -> Self {
+        Self {
+            ram_enabled: false,
+            rom_bank: 0x01,
+        }
+    }
+    /// Both registers live in 0x0000-0x3FFF, address bit 8 picks which one.
+    pub fn write_register(&mut self, address: u16, value: u8) {
+        match address {
+            0x0000..=0x3FFF if address & 0x0100 == 0 => self.ram_enabled = value & 0x0F == 0x0A,
+            0x0000..=0x3FFF => {
+                self.rom_bank = match value & 0x0F {
+                    0x00 => 0x01,
+                    bank => bank,
+                }
+            }
+            0x4000..=0x7FFF => (),
+            _ => unreachable!(),
+        }
+    }
+    pub fn rom_offset(&self, address: u16) -> usize {
+        let bank = match address {
+            0x0000..=0x3FFF => 0,
+            0x4000..=0x7FFF => self.rom_bank,
+            _ => unreachable!(),
+        };
+        bank as usize * ROM_BANK_SIZE + (address as usize & (ROM_BANK_SIZE - 1))
+    }
+    /// Only the bottom nine address bits are decoded, so the 512 cells repeat
+    /// through the whole of 0xA000-0xBFFF.
+    pub fn ram_offset(&self, address: u16) -> Option<usize> {
+        match self.ram_enabled {
+            true => Some((address as usize) & (MBC2_RAM_SIZE - 1)),
+            false => None,
+        }
+    }
+}
+
+impl SaveState for Mbc2 {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_bool(self.ram_enabled);
+        writer.write_u8(self.rom_bank);
+    }
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), SaveStateError> {
+        self.ram_enabled = reader.read_bool()?;
+        self.rom_bank = reader.read_u8()? & 0x0F;
+        Ok(())
+    }
+}
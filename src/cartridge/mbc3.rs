@@ -0,0 +1,118 @@
+use std::sync::Arc;
+
+use crate::{
+    cartridge::{
+        RAM_BANK_SIZE, ROM_BANK_SIZE,
+        rtc::{Rtc, RtcClock},
+    },
+    savestate::{SaveState, SaveStateError, StateReader, StateWriter},
+};
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Mbc3 {
+    ram_and_rtc_enabled: bool,
+    rom_bank: u8,
+    /// RAM bank 0x00-0x07 or RTC register 0x08-0x0C written to 0x4000-0x5FFF.
+    ram_select: u8,
+    last_latch_write: u8,
+    rtc: Option<Rtc>,
+}
+impl Mbc3 {
+    pub fn new(rtc: Option<Rtc>) -> Self {
+        Self {
+            ram_and_rtc_enabled: false,
+            rom_bank: 0x01,
+            ram_select: 0x00,
+            last_latch_write: 0xFF,
+            rtc,
+        }
+    }
+    pub fn rtc(&self) -> Option<&Rtc> {
+        self.rtc.as_ref()
+    }
+    pub fn rtc_mut(&mut self) -> Option<&mut Rtc> {
+        self.rtc.as_mut()
+    }
+    pub fn set_rtc_clock(&mut self, clock: Arc<dyn RtcClock>) {
+        if let Some(rtc) = &mut self.rtc {
+            rtc.set_clock(clock);
+        }
+    }
+    pub fn write_register(&mut self, address: u16, value: u8) {
+        match address {
+            0x0000..=0x1FFF => self.ram_and_rtc_enabled = value & 0x0F == 0x0A,
+            0x2000..=0x3FFF => {
+                self.rom_bank = match value & 0x7F {
+                    0x00 => 0x01,
+                    bank => bank,
+                }
+            }
+            0x4000..=0x5FFF => self.ram_select = value & 0x0F,
+            0x6000..=0x7FFF => {
+                // The clock is latched by writing 0x00 followed by 0x01.
+                if self.last_latch_write == 0x00
+                    && value == 0x01
+                    && let Some(rtc) = &mut self.rtc
+                {
+                    rtc.latch();
+                }
+                self.last_latch_write = value;
+            }
+            _ => unreachable!(),
+        }
+    }
+    pub fn rom_offset(&self, address: u16) -> usize {
+        let bank = match address {
+            0x0000..=0x3FFF => 0,
+            0x4000..=0x7FFF => self.rom_bank,
+            _ => unreachable!(),
+        };
+        bank as usize * ROM_BANK_SIZE + (address as usize & (ROM_BANK_SIZE - 1))
+    }
+    pub fn ram_offset(&self, address: u16) -> Option<usize> {
+        match (self.ram_and_rtc_enabled, self.ram_select) {
+            (true, bank @ 0x00..=0x07) => {
+                Some(bank as usize * RAM_BANK_SIZE + (address - 0xA000) as usize)
+            }
+            _ => None,
+        }
+    }
+    pub fn rtc_selected(&self) -> bool {
+        self.ram_and_rtc_enabled && self.rtc.is_some() && (0x08..=0x0C).contains(&self.ram_select)
+    }
+    pub fn read_rtc(&self) -> u8 {
+        match &self.rtc {
+            Some(rtc) => rtc.read(self.ram_select),
+            None => 0xFF,
+        }
+    }
+    pub fn write_rtc(&mut self, value: u8) {
+        if let Some(rtc) = &mut self.rtc {
+            rtc.write(self.ram_select, value);
+        }
+    }
+}
+
+impl SaveState for Mbc3 {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_bool(self.ram_and_rtc_enabled);
+        writer.write_u8(self.rom_bank);
+        writer.write_u8(self.ram_select);
+        writer.write_u8(self.last_latch_write);
+        writer.write_bool(self.rtc.is_some());
+        if let Some(rtc) = &self.rtc {
+            rtc.save_state(writer);
+        }
+    }
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), SaveStateError> {
+        self.ram_and_rtc_enabled = reader.read_bool()?;
+        self.rom_bank = reader.read_u8()? & 0x7F;
+        self.ram_select = reader.read_u8()? & 0x0F;
+        self.last_latch_write = reader.read_u8()?;
+        match (reader.read_bool()?, &mut self.rtc) {
+            (true, Some(rtc)) => rtc.load_state(reader),
+            (false, None) => Ok(()),
+            _ => Err(SaveStateError::CartridgeMismatch),
+        }
+    }
+}
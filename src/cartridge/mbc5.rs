@@ -0,0 +1,77 @@
+use crate::cartridge::{RAM_BANK_SIZE, ROM_BANK_SIZE};
+use crate::savestate::{SaveState, SaveStateError, StateReader, StateWriter};
+
+const RUMBLE_MOTOR: u8 = 0b0000_1000;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Mbc5 {
+    ram_enabled: bool,
+    /// Nine bit ROM bank, split over 0x2000-0x2FFF and 0x3000-0x3FFF.
+    rom_bank: u16,
+    ram_bank: u8,
+    /// Rumble cartridges wire bit 3 of the RAM bank register to the motor.
+    has_rumble: bool,
+    rumble_active: bool,
+}
+impl Mbc5 {
+    pub fn new(has_rumble: bool) -> Self {
+        Self {
+            ram_enabled: false,
+            rom_bank: 0x0001,
+            ram_bank: 0x00,
+            has_rumble,
+            rumble_active: false,
+        }
+    }
+    pub fn rumble_active(&self) -> bool {
+        self.rumble_active
+    }
+    pub fn write_register(&mut self, address: u16, value: u8) {
+        match address {
+            0x0000..=0x1FFF => self.ram_enabled = value & 0x0F == 0x0A,
+            0x2000..=0x2FFF => self.rom_bank = (self.rom_bank & 0x0100) | value as u16,
+            0x3000..=0x3FFF => {
+                self.rom_bank = (self.rom_bank & 0x00FF) | ((value as u16 & 0x01) << 8)
+            }
+            0x4000..=0x5FFF => match self.has_rumble {
+                true => {
+                    self.rumble_active = value & RUMBLE_MOTOR != 0;
+                    self.ram_bank = value & 0x07;
+                }
+                false => self.ram_bank = value & 0x0F,
+            },
+            0x6000..=0x7FFF => (),
+            _ => unreachable!(),
+        }
+    }
+    pub fn rom_offset(&self, address: u16) -> usize {
+        let bank = match address {
+            0x0000..=0x3FFF => 0,
+            0x4000..=0x7FFF => self.rom_bank,
+            _ => unreachable!(),
+        };
+        bank as usize * ROM_BANK_SIZE + (address as usize & (ROM_BANK_SIZE - 1))
+    }
+    pub fn ram_offset(&self, address: u16) -> Option<usize> {
+        match self.ram_enabled {
+            true => Some(self.ram_bank as usize * RAM_BANK_SIZE + (address - 0xA000) as usize),
+            false => None,
+        }
+    }
+}
+
+impl SaveState for Mbc5 {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_bool(self.ram_enabled);
+        writer.write_u16(self.rom_bank);
+        writer.write_u8(self.ram_bank);
+        writer.write_bool(self.rumble_active);
+    }
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), SaveStateError> {
+        self.ram_enabled = reader.read_bool()?;
+        self.rom_bank = reader.read_u16()? & 0x01FF;
+        self.ram_bank = reader.read_u8()? & 0x0F;
+        self.rumble_active = reader.read_bool()?;
+        Ok(())
+    }
+}
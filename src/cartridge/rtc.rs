@@ -0,0 +1,338 @@
+use std::{
+    fmt,
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crate::savestate::{SaveState, SaveStateError, StateReader, StateWriter};
+
+const SECONDS_PER_DAY: u64 = 86_400;
+/// T-cycles of the normal speed clock in a second of emulated time.
+const CYCLES_PER_SECOND: u64 = 4_194_304;
+/// Size of the RTC footer appended to `.sav` files by BGB and VBA-M: the
+/// current and latched registers as ten little endian u32 values followed by
+/// a u64 Unix timestamp. Older files use a u32 timestamp instead.
+pub const RTC_FOOTER_SIZE: usize = 48;
+pub const RTC_FOOTER_SIZE_LEGACY: usize = 44;
+const DAY_HIGH_DAY_BIT: u8 = 0b0000_0001;
+const DAY_HIGH_HALT: u8 = 0b0100_0000;
+const DAY_HIGH_CARRY: u8 = 0b1000_0000;
+
+/// Source of host time for the cartridge clock, measured from the Unix epoch.
+/// Save files record it so the clock can catch up on time spent switched off.
+/// Setting one on a cartridge runs the clock on it instead of emulated time.
+pub trait RtcClock: Send + Sync {
+    fn now(&self) -> Duration;
+}
+
+/// Wall clock time. Also used for the save file timestamp while the clock
+/// runs on emulated time.
+#[derive(Default)]
+pub struct SystemClock;
+impl RtcClock for SystemClock {
+    fn now(&self) -> Duration {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+    }
+}
+
+/// A clock that only moves when told to.
+#[derive(Default)]
+pub struct ManualClock {
+    nanos: AtomicU64,
+}
+impl ManualClock {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    pub fn advance(&self, duration: Duration) {
+        self.nanos
+            .fetch_add(duration.as_nanos() as u64, Ordering::SeqCst);
+    }
+}
+impl RtcClock for ManualClock {
+    fn now(&self) -> Duration {
+        Duration::from_nanos(self.nanos.load(Ordering::SeqCst))
+    }
+}
+
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct RtcRegisters {
+    pub seconds: u8,
+    pub minutes: u8,
+    pub hours: u8,
+    pub day_low: u8,
+    pub day_high: u8,
+}
+impl RtcRegisters {
+    fn days(&self) -> u16 {
+        ((self.day_high & DAY_HIGH_DAY_BIT) as u16) << 8 | self.day_low as u16
+    }
+    fn set_days(&mut self, days: u16) {
+        self.day_low = days as u8;
+        self.day_high =
+            (self.day_high & !DAY_HIGH_DAY_BIT) | ((days >> 8) as u8 & DAY_HIGH_DAY_BIT);
+    }
+    pub fn halted(&self) -> bool {
+        self.day_high & DAY_HIGH_HALT != 0
+    }
+    fn in_range(&self) -> bool {
+        self.seconds < 60 && self.minutes < 60 && self.hours < 24
+    }
+    /// Counts a single second. Values a game wrote outside the normal range
+    /// keep counting up to the width of their register and then wrap to zero
+    /// without carrying into the next one.
+    fn tick_second(&mut self) {
+        if self.seconds != 59 {
+            self.seconds = (self.seconds + 1) & 0x3F;
+            return;
+        }
+        self.seconds = 0;
+        if self.minutes != 59 {
+            self.minutes = (self.minutes + 1) & 0x3F;
+            return;
+        }
+        self.minutes = 0;
+        if self.hours != 23 {
+            self.hours = (self.hours + 1) & 0x1F;
+            return;
+        }
+        self.hours = 0;
+        self.add_days(1);
+    }
+    fn add_days(&mut self, days: u64) {
+        let days = self.days() as u64 + days;
+        if days > 0x1FF {
+            self.day_high |= DAY_HIGH_CARRY;
+        }
+        self.set_days((days & 0x1FF) as u16);
+    }
+    fn advance(&mut self, mut seconds: u64) {
+        while seconds > 0 && !self.in_range() {
+            self.tick_second();
+            seconds -= 1;
+        }
+        if seconds == 0 {
+            return;
+        }
+        let time_of_day =
+            self.seconds as u64 + self.minutes as u64 * 60 + self.hours as u64 * 3600 + seconds;
+        self.seconds = (time_of_day % 60) as u8;
+        self.minutes = (time_of_day / 60 % 60) as u8;
+        self.hours = (time_of_day / 3600 % 24) as u8;
+        let days = time_of_day / SECONDS_PER_DAY;
+        if days > 0 {
+            self.add_days(days);
+        }
+    }
+}
+
+/// The MBC3 real time clock. Time is only brought up to date when the game
+/// touches the clock, by measuring how far its time source has moved since.
+/// That is emulated time counted by `tick` unless a host clock is set.
+#[derive(Clone)]
+pub struct Rtc {
+    clock: Option<Arc<dyn RtcClock>>,
+    /// Normal speed T-cycles run since power on.
+    cycles: u64,
+    last_update: Duration,
+    subsecond: Duration,
+    current: RtcRegisters,
+    latched: RtcRegisters,
+}
+impl Rtc {
+    pub fn new() -> Self {
+        Self {
+            clock: None,
+            cycles: 0,
+            last_update: Duration::ZERO,
+            subsecond: Duration::ZERO,
+            current: RtcRegisters::default(),
+            latched: RtcRegisters::default(),
+        }
+    }
+    pub fn set_clock(&mut self, clock: Arc<dyn RtcClock>) {
+        self.update();
+        self.last_update = clock.now();
+        self.clock = Some(clock);
+    }
+    /// Moves emulated time on by `cycles` T-cycles of the normal clock.
+    pub fn tick(&mut self, cycles: u32) {
+        self.cycles += cycles as u64;
+    }
+    fn now(&self) -> Duration {
+        match &self.clock {
+            Some(clock) => clock.now(),
+            None => {
+                let nanos = (self.cycles % CYCLES_PER_SECOND) * 1_000_000_000 / CYCLES_PER_SECOND;
+                Duration::new(self.cycles / CYCLES_PER_SECOND, nanos as u32)
+            }
+        }
+    }
+    /// Host time for the save file footer, which has to be comparable
+    /// across runs.
+    fn host_now(&self) -> Duration {
+        match &self.clock {
+            Some(clock) => clock.now(),
+            None => SystemClock.now(),
+        }
+    }
+    pub fn update(&mut self) {
+        let now = self.now();
+        let elapsed = now.saturating_sub(self.last_update);
+        self.last_update = now;
+        if self.current.halted() {
+            return;
+        }
+        let elapsed = self.subsecond + elapsed;
+        self.subsecond = Duration::from_nanos(elapsed.subsec_nanos() as u64);
+        self.current.advance(elapsed.as_secs());
+    }
+    /// The running registers as they would read right now.
+    pub fn current(&self) -> RtcRegisters {
+        let mut rtc = self.clone();
+        rtc.update();
+        rtc.current
+    }
+    pub fn latched(&self) -> RtcRegisters {
+        self.latched
+    }
+    pub fn latch(&mut self) {
+        self.update();
+        self.latched = self.current;
+    }
+    /// Reads the latched copy of the register selected by 0x08-0x0C.
+    pub fn read(&self, select: u8) -> u8 {
+        match select {
+            0x08 => self.latched.seconds,
+            0x09 => self.latched.minutes,
+            0x0A => self.latched.hours,
+            0x0B => self.latched.day_low,
+            0x0C => self.latched.day_high,
+            _ => 0xFF,
+        }
+    }
+    pub fn write(&mut self, select: u8, value: u8) {
+        self.update();
+        match select {
+            0x08 => {
+                self.current.seconds = value & 0x3F;
+                self.subsecond = Duration::ZERO;
+            }
+            0x09 => self.current.minutes = value & 0x3F,
+            0x0A => self.current.hours = value & 0x1F,
+            0x0B => self.current.day_low = value,
+            0x0C => {
+                self.current.day_high = value & (DAY_HIGH_CARRY | DAY_HIGH_HALT | DAY_HIGH_DAY_BIT)
+            }
+            _ => (),
+        }
+    }
+    pub fn footer(&self) -> Vec<u8> {
+        let current = self.current();
+        let mut footer = Vec::with_capacity(RTC_FOOTER_SIZE);
+        for registers in [current, self.latched] {
+            for register in [
+                registers.seconds,
+                registers.minutes,
+                registers.hours,
+                registers.day_low,
+                registers.day_high,
+            ] {
+                footer.extend_from_slice(&(register as u32).to_le_bytes());
+            }
+        }
+        footer.extend_from_slice(&self.host_now().as_secs().to_le_bytes());
+        footer
+    }
+    /// Restores the registers from a save file footer, then moves the clock
+    /// forward by the time that passed since the file was written.
+    pub fn load_footer(&mut self, footer: &[u8]) {
+        let register = |index: usize| footer[index * 4];
+        let registers = |start: usize| RtcRegisters {
+            seconds: register(start) & 0x3F,
+            minutes: register(start + 1) & 0x3F,
+            hours: register(start + 2) & 0x1F,
+            day_low: register(start + 3),
+            day_high: register(start + 4) & (DAY_HIGH_CARRY | DAY_HIGH_HALT | DAY_HIGH_DAY_BIT),
+        };
+        self.current = registers(0);
+        self.latched = registers(5);
+        let saved_at = match footer.len() {
+            RTC_FOOTER_SIZE => u64::from_le_bytes(footer[40..48].try_into().unwrap()),
+            _ => u32::from_le_bytes(footer[40..44].try_into().unwrap()) as u64,
+        };
+        self.subsecond = Duration::ZERO;
+        self.last_update = self.now();
+        if !self.current.halted() {
+            self.current
+                .advance(self.host_now().as_secs().saturating_sub(saved_at));
+        }
+    }
+}
+impl SaveState for RtcRegisters {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_u8(self.seconds);
+        writer.write_u8(self.minutes);
+        writer.write_u8(self.hours);
+        writer.write_u8(self.day_low);
+        writer.write_u8(self.day_high);
+    }
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), SaveStateError> {
+        self.seconds = reader.read_u8()? & 0x3F;
+        self.minutes = reader.read_u8()? & 0x3F;
+        self.hours = reader.read_u8()? & 0x1F;
+        self.day_low = reader.read_u8()?;
+        self.day_high = reader.read_u8()? & (DAY_HIGH_CARRY | DAY_HIGH_HALT | DAY_HIGH_DAY_BIT);
+        Ok(())
+    }
+}
+/// The clock carries on from the moment the state is loaded, time that
+/// passed since the state was made is not added.
+impl SaveState for Rtc {
+    fn save_state(&self, writer: &mut StateWriter) {
+        let mut rtc = self.clone();
+        rtc.update();
+        writer.write_u32(rtc.subsecond.subsec_nanos());
+        rtc.current.save_state(writer);
+        rtc.latched.save_state(writer);
+    }
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), SaveStateError> {
+        let nanos = reader.read_u32()?;
+        if nanos >= 1_000_000_000 {
+            return Err(SaveStateError::Corrupt(
+                "RTC sub-second counter out of range",
+            ));
+        }
+        self.subsecond = Duration::from_nanos(nanos as u64);
+        self.current.load_state(reader)?;
+        self.latched.load_state(reader)?;
+        self.last_update = self.now();
+        Ok(())
+    }
+}
+impl Default for Rtc {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+impl fmt::Debug for Rtc {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Rtc")
+            .field("subsecond", &self.subsecond)
+            .field("current", &self.current)
+            .field("latched", &self.latched)
+            .finish()
+    }
+}
+impl PartialEq for Rtc {
+    fn eq(&self, other: &Self) -> bool {
+        self.subsecond == other.subsecond
+            && self.current == other.current
+            && self.latched == other.latched
+    }
+}
@@ -7,16 +7,28 @@ use crate::cpu::opfunctions::*;
 use crate::cpu::parse::*;
 use crate::cpu::structs::*;
 use crate::graphics::Display;
+use crate::savestate::{SaveState, SaveStateError, StateReader, StateWriter};
 use crate::{memory::Memory, memory::MemoryMap};
 
 mod asm;
 mod enums;
 mod structs;
 
+/// Cycles that pass for each call while the CPU is halted or stopped.
+const IDLE_CYCLES: u32 = 4;
+
 #[derive(Copy, Clone, Debug, PartialEq)]
 pub struct Cpu {
     pub registers: Registers,
     pub is_running: bool,
+    pub mode: Mode,
+    /// Set by HALT, cleared once `IE & IF` is non-zero.
+    pub halted: bool,
+    /// Set by STOP, cleared once a selected joypad input goes low.
+    pub stopped: bool,
+    /// HALT with IME=0 and an interrupt already pending doesn't halt, instead
+    /// the byte after it is fetched twice.
+    pub halt_bug: bool,
 }
 #[allow(dead_code)]
 impl Cpu {
@@ -24,6 +36,10 @@ impl Cpu {
         match mode {
             Mode::DMG => Cpu {
                 is_running: true,
+                mode,
+                halted: false,
+                stopped: false,
+                halt_bug: false,
                 registers: Registers {
                     af: AccumulatorAndFlags {
                         accumulator: 0x01,
@@ -40,12 +56,16 @@ impl Cpu {
                     },
                     ime: InterruptMasterEnable {
                         ime: false,
-                        has_waited: false,
+                        enable_pending: false,
                     },
                 },
             },
             Mode::GBC => Cpu {
                 is_running: true,
+                mode,
+                halted: false,
+                stopped: false,
+                halt_bug: false,
                 registers: Registers {
                     af: AccumulatorAndFlags {
                         accumulator: 0x11,
@@ -62,7 +82,7 @@ impl Cpu {
                     },
                     ime: InterruptMasterEnable {
                         ime: false,
-                        has_waited: false,
+                        enable_pending: false,
                     },
                 },
             },
@@ -73,11 +93,11 @@ impl Cpu {
         self.mask_bootrom(*bootrom, memory);
         self.registers.pc.programcounter = 0x0000;
         while self.registers.pc.programcounter != 0x0100 {
-            self.execute_next_instruction(memory);
+            let cycles = self.execute_next_instruction(memory);
             display
                 .lock()
                 .expect("failed to unlock display mutex")
-                .update(memory);
+                .update(memory, cycles);
         }
         println!("bootrom complete")
     }
@@ -94,6 +114,10 @@ impl Cpu {
     }
     fn get_next_operation(&mut self, memory: &MemoryMap) -> Operation {
         let opcode_byte = self.get_next_byte(memory);
+        if self.halt_bug {
+            self.halt_bug = false;
+            self.registers.pc.programcounter = self.registers.pc.programcounter.wrapping_sub(1);
+        }
         let mut cb_byte = None;
         if opcode_byte == 0xCB {
             cb_byte = Some(self.get_next_byte(memory));
@@ -115,16 +139,28 @@ impl Cpu {
         }
         operation
     }
-    pub fn execute_next_instruction(&mut self, memory: &mut MemoryMap) {
-        if self.registers.ime.ime {
-            if self.registers.ime.has_waited {
-                self.registers.ime.has_waited = false;
-                self.registers.ime.ime = false;
+    /// Runs one instruction, or services an interrupt, and returns the
+    /// T-cycles it took. While halted or stopped each call idles for 4.
+    pub fn execute_next_instruction(&mut self, memory: &mut MemoryMap) -> u32 {
+        if self.stopped {
+            match memory.joypad_input_low() {
+                true => self.stopped = false,
+                false => return IDLE_CYCLES,
             }
-            if self.registers.ime.ime {
-                self.registers.ime.has_waited = true;
+        }
+        if self.halted {
+            match memory.pending_interrupts() {
+                0 => return IDLE_CYCLES,
+                _ => self.halted = false,
             }
         }
+        let dispatch_cycles = InterruptMasterEnable::check_interrupts(self, memory);
+        if dispatch_cycles != 0 {
+            return dispatch_cycles;
+        }
+        let enable_interrupts = self.registers.ime.enable_pending;
+        let mut branch_taken = false;
+        let mut cb_cycles = None;
         let operation = self.get_next_operation(memory);
 
         let mut i8_value: Option<i8> = None;
@@ -142,8 +178,8 @@ impl Cpu {
         match operation.opcode {
             Opcode::DAA => daa_operation(self),
             Opcode::PANIC => crash(self),
-            Opcode::STOP => todo!(),
-            Opcode::HALT => todo!(),
+            Opcode::STOP => stop_operation(self, memory),
+            Opcode::HALT => halt_operation(self, memory),
             Opcode::LD(target1, target2) => ld_operation(
                 self,
                 target1,
@@ -159,20 +195,24 @@ impl Cpu {
             Opcode::CCF => ccf_operation(self),
             Opcode::CPL => cpl_operation(self),
             Opcode::SCF => scf_operation(self),
-            Opcode::JP(condition, target) => jp_operation(
-                self,
-                condition,
-                target,
-                operation.value_one,
-                operation.value_two,
-            ),
-            Opcode::JR(condition) => jr_operation(
-                self,
-                condition,
-                operation
-                    .value_one
-                    .expect("No value given for JR operation") as i8,
-            ),
+            Opcode::JP(condition, target) => {
+                branch_taken = jp_operation(
+                    self,
+                    condition,
+                    target,
+                    operation.value_one,
+                    operation.value_two,
+                )
+            }
+            Opcode::JR(condition) => {
+                branch_taken = jr_operation(
+                    self,
+                    condition,
+                    operation
+                        .value_one
+                        .expect("No value given for JR operation") as i8,
+                )
+            }
             Opcode::RRA => rra_operation(self),
             Opcode::RRCA => rrca_operation(self),
             Opcode::RLA => rla_operation(self),
@@ -193,27 +233,122 @@ impl Cpu {
             Opcode::OR(source) => or_operation(self, source, operation.value_one, memory),
             Opcode::POP(register) => pop_operation(self, register, memory),
             Opcode::PUSH(source) => push_operation(self, source, memory),
-            Opcode::RET(condition) => ret_operation(self, condition, memory),
+            Opcode::RET(condition) => branch_taken = ret_operation(self, condition, memory),
             Opcode::RETI => reti_operation(self, memory),
-            Opcode::CALL(condition) => call_operation(
-                self,
-                condition,
-                operation.value_one,
-                operation.value_two,
-                memory,
-            ),
+            Opcode::CALL(condition) => {
+                branch_taken = call_operation(
+                    self,
+                    condition,
+                    operation.value_one,
+                    operation.value_two,
+                    memory,
+                )
+            }
             Opcode::RST(rst_addr) => rst_operation(self, rst_addr, memory),
             Opcode::EI => ei_operation(self),
             Opcode::DI => di_operation(self),
-            Opcode::CB(cbcode) => self.execute_cb_instruction(cbcode, memory),
+            Opcode::CB(cbcode) => cb_cycles = Some(self.execute_cb_instruction(cbcode, memory)),
+        }
+        if enable_interrupts && self.registers.ime.enable_pending {
+            self.registers.ime.enable_pending = false;
+            self.registers.ime.ime = true;
         }
+        cb_cycles.unwrap_or_else(|| operation.cycles.cycles(branch_taken))
     }
 
-    fn execute_cb_instruction(&mut self, cbcode: CBPrefix, memory: &mut MemoryMap) {
+    /// CB instructions carry their own timing, which includes the prefix.
+    fn execute_cb_instruction(&mut self, cbcode: CBPrefix, memory: &mut MemoryMap) -> u32 {
         match cbcode {
-            CBPrefix::BIT(value, target, timing) => bit_operation(self, target, value, memory),
-            CBPrefix::RRC(target, timing) => rrc_operation(self, target, memory),
-            _ => todo!(),
+            CBPrefix::RLC(target, timing) => {
+                rlc_operation(self, target, memory);
+                timing.cycles(false)
+            }
+            CBPrefix::RRC(target, timing) => {
+                rrc_operation(self, target, memory);
+                timing.cycles(false)
+            }
+            CBPrefix::RL(target, timing) => {
+                rl_operation(self, target, memory);
+                timing.cycles(false)
+            }
+            CBPrefix::RR(target, timing) => {
+                rr_operation(self, target, memory);
+                timing.cycles(false)
+            }
+            CBPrefix::SLA(target, timing) => {
+                sla_operation(self, target, memory);
+                timing.cycles(false)
+            }
+            CBPrefix::SRA(target, timing) => {
+                sra_operation(self, target, memory);
+                timing.cycles(false)
+            }
+            CBPrefix::SWAP(target, timing) => {
+                swap_operation(self, target, memory);
+                timing.cycles(false)
+            }
+            CBPrefix::SRL(target, timing) => {
+                srl_operation(self, target, memory);
+                timing.cycles(false)
+            }
+            CBPrefix::BIT(value, target, timing) => {
+                bit_operation(self, target, value, memory);
+                timing.cycles(false)
+            }
+            CBPrefix::RES(value, target, timing) => {
+                res_operation(self, target, value, memory);
+                timing.cycles(false)
+            }
+            CBPrefix::SET(value, target, timing) => {
+                set_operation(self, target, value, memory);
+                timing.cycles(false)
+            }
+        }
+    }
+}
+
+impl SaveState for Cpu {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_u8(self.registers.af.accumulator);
+        writer.write_u8(self.registers.af.flags.bits);
+        writer.write_u8(self.registers.bc.b);
+        writer.write_u8(self.registers.bc.c);
+        writer.write_u8(self.registers.de.d);
+        writer.write_u8(self.registers.de.e);
+        writer.write_u8(self.registers.hl.h);
+        writer.write_u8(self.registers.hl.l);
+        writer.write_u16(self.registers.sp.stackpointer);
+        writer.write_u16(self.registers.pc.programcounter);
+        writer.write_bool(self.registers.ime.ime);
+        writer.write_bool(self.registers.ime.enable_pending);
+        writer.write_bool(self.is_running);
+        writer.write_u8(self.mode as u8);
+        writer.write_bool(self.halted);
+        writer.write_bool(self.stopped);
+        writer.write_bool(self.halt_bug);
+    }
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), SaveStateError> {
+        self.registers.af.accumulator = reader.read_u8()?;
+        self.registers.af.flags.bits = reader.read_u8()?;
+        self.registers.bc.b = reader.read_u8()?;
+        self.registers.bc.c = reader.read_u8()?;
+        self.registers.de.d = reader.read_u8()?;
+        self.registers.de.e = reader.read_u8()?;
+        self.registers.hl.h = reader.read_u8()?;
+        self.registers.hl.l = reader.read_u8()?;
+        self.registers.sp.stackpointer = reader.read_u16()?;
+        self.registers.pc.programcounter = reader.read_u16()?;
+        self.registers.ime.ime = reader.read_bool()?;
+        self.registers.ime.enable_pending = reader.read_bool()?;
+        self.is_running = reader.read_bool()?;
+        if reader.read_u8()? != self.mode as u8 {
+            return Err(SaveStateError::Corrupt(
+                "state was made in a different mode",
+            ));
         }
+        self.halted = reader.read_bool()?;
+        self.stopped = reader.read_bool()?;
+        self.halt_bug = reader.read_bool()?;
+        Ok(())
     }
 }
@@ -7,17 +7,28 @@ pub fn crash(cpu: &mut Cpu) {
 }
 
 pub fn di_operation(cpu: &mut Cpu) {
-    cpu.registers.ime.ime = false
+    cpu.registers.ime.ime = false;
+    cpu.registers.ime.enable_pending = false;
 }
 
 pub fn ei_operation(cpu: &mut Cpu) {
-    cpu.registers.ime.ime = true
+    cpu.registers.ime.enable_pending = true
 }
 
-pub fn halt_operation(cpu: &mut Cpu) {
-    match cpu.registers.ime.ime {
-        true => cpu.is_running = false,
-        false => todo!(),
+pub fn halt_operation(cpu: &mut Cpu, memory_map: &MemoryMap) {
+    match !cpu.registers.ime.ime && memory_map.pending_interrupts() != 0 {
+        true => cpu.halt_bug = true,
+        false => cpu.halted = true,
+    }
+}
+
+pub fn stop_operation(cpu: &mut Cpu, memory_map: &mut MemoryMap) {
+    memory_map.write(0xFF04, 0x00);
+    match cpu.mode {
+        Mode::GBC if memory_map.io_registers.key1.switch_armed() => {
+            memory_map.io_registers.key1.switch_speed()
+        }
+        _ => cpu.stopped = true,
     }
 }
 
@@ -95,15 +106,15 @@ pub fn call_operation(
     value_one: Option<u8>,
     value_two: Option<u8>,
     memory_map: &mut MemoryMap,
-) {
+) -> bool {
     let value_one = value_one.expect("No value given for CALL operation");
     let value_two = value_two.expect("8 bit value given for 16 bit CALL operation");
     let target = (value_one as u16) << 8 | value_two as u16;
     match condition {
-        Condition::C if !cpu.registers.af.flags.get(Flag::C) => return,
-        Condition::NC if cpu.registers.af.flags.get(Flag::C) => return,
-        Condition::Z if !cpu.registers.af.flags.get(Flag::Z) => return,
-        Condition::NZ if cpu.registers.af.flags.get(Flag::Z) => return,
+        Condition::C if !cpu.registers.af.flags.get(Flag::C) => return false,
+        Condition::NC if cpu.registers.af.flags.get(Flag::C) => return false,
+        Condition::Z if !cpu.registers.af.flags.get(Flag::Z) => return false,
+        Condition::NZ if cpu.registers.af.flags.get(Flag::Z) => return false,
         _ => (),
     }
 
@@ -112,7 +123,8 @@ pub fn call_operation(
     memory_map.write(cpu.registers.sp.stackpointer, pc_high_byte);
     cpu.registers.sp.stackpointer -= 1;
     memory_map.write(cpu.registers.sp.stackpointer, pc_low_byte);
-    cpu.registers.write_u16(Register::PC, target)
+    cpu.registers.write_u16(Register::PC, target);
+    true
 }
 
 pub fn rst_operation(cpu: &mut Cpu, target: RSTAddr, memory_map: &mut MemoryMap) {
@@ -134,13 +146,12 @@ pub fn rst_operation(cpu: &mut Cpu, target: RSTAddr, memory_map: &mut MemoryMap)
     cpu.registers.write_u16(Register::PC, target_value);
 }
 
-pub fn ret_operation(cpu: &mut Cpu, condition: Condition, memory_map: &mut MemoryMap) {
-    println!("We have decoded the logo");
+pub fn ret_operation(cpu: &mut Cpu, condition: Condition, memory_map: &mut MemoryMap) -> bool {
     match condition {
-        Condition::C if !cpu.registers.af.flags.get(Flag::C) => return,
-        Condition::NC if cpu.registers.af.flags.get(Flag::C) => return,
-        Condition::Z if !cpu.registers.af.flags.get(Flag::Z) => return,
-        Condition::NZ if cpu.registers.af.flags.get(Flag::Z) => return,
+        Condition::C if !cpu.registers.af.flags.get(Flag::C) => return false,
+        Condition::NC if cpu.registers.af.flags.get(Flag::C) => return false,
+        Condition::Z if !cpu.registers.af.flags.get(Flag::Z) => return false,
+        Condition::NZ if cpu.registers.af.flags.get(Flag::Z) => return false,
         _ => (),
     }
     let sp = OpTarget::Register(Register::SP);
@@ -148,7 +159,8 @@ pub fn ret_operation(cpu: &mut Cpu, condition: Condition, memory_map: &mut Memor
     inc_operation(cpu, sp, memory_map);
     let high_byte = memory_map.read(cpu.registers.sp.stackpointer);
     inc_operation(cpu, sp, memory_map);
-    cpu.registers.sp.stackpointer = (high_byte as u16) << 8 | low_byte as u16;
+    cpu.registers.pc.programcounter = (high_byte as u16) << 8 | low_byte as u16;
+    true
 }
 
 pub fn reti_operation(cpu: &mut Cpu, memory_map: &mut MemoryMap) {
@@ -157,7 +169,7 @@ pub fn reti_operation(cpu: &mut Cpu, memory_map: &mut MemoryMap) {
     inc_operation(cpu, sp, memory_map);
     let high_byte = memory_map.read(cpu.registers.sp.stackpointer);
     inc_operation(cpu, sp, memory_map);
-    cpu.registers.sp.stackpointer = (high_byte as u16) << 8 | low_byte as u16;
+    cpu.registers.pc.programcounter = (high_byte as u16) << 8 | low_byte as u16;
     cpu.registers.ime.ime = true;
 }
 
@@ -706,10 +718,29 @@ pub fn rra_operation(cpu: &mut Cpu) {
     cpu.registers.af.flags.set(Flag::H, false);
 }
 
-pub fn rrc_operation(cpu: &mut Cpu, target: OpTarget, memory_map: &mut MemoryMap) {
+fn cb_target_value(cpu: &mut Cpu, target: OpTarget, memory_map: &MemoryMap) -> u8 {
+    match target {
+        OpTarget::Register(register) => match register {
+            Register::A => cpu.registers.af.accumulator,
+            Register::B => cpu.registers.bc.b,
+            Register::C => cpu.registers.bc.c,
+            Register::D => cpu.registers.de.d,
+            Register::E => cpu.registers.de.e,
+            Register::H => cpu.registers.hl.h,
+            Register::L => cpu.registers.hl.l,
+            _ => panic!("Invalid register given to CB instruction"),
+        },
+        OpTarget::Value(ValueType::deref(DerefSource::Register(Register::HL(HLMode::Normal)))) => {
+            memory_map.read(cpu.registers.read_u16(Register::HL(HLMode::Normal)))
+        }
+        _ => panic!("Invalid target given to CB instruction"),
+    }
+}
+
+fn set_cb_target_value(cpu: &mut Cpu, target: OpTarget, value: u8, memory_map: &mut MemoryMap) {
     match target {
         OpTarget::Register(register) => {
-            let source = match register {
+            let dest = match register {
                 Register::A => &mut cpu.registers.af.accumulator,
                 Register::B => &mut cpu.registers.bc.b,
                 Register::C => &mut cpu.registers.bc.c,
@@ -717,30 +748,96 @@ pub fn rrc_operation(cpu: &mut Cpu, target: OpTarget, memory_map: &mut MemoryMap
                 Register::E => &mut cpu.registers.de.e,
                 Register::H => &mut cpu.registers.hl.h,
                 Register::L => &mut cpu.registers.hl.l,
-                _ => panic!("Invalid register given to RRC instruction"),
+                _ => panic!("Invalid register given to CB instruction"),
             };
-            let old_bit0 = *source & 0x01;
-            *source >>= 1;
-            *source |= old_bit0 << 7;
-            cpu.registers.af.flags.set(Flag::C, old_bit0 != 0);
-            if *source == 0 {
-                cpu.registers.af.flags.set(Flag::Z, true)
-            }
+            *dest = value;
         }
         OpTarget::Value(ValueType::deref(DerefSource::Register(Register::HL(HLMode::Normal)))) => {
-            let addr = cpu.registers.read_u16(Register::HL(HLMode::Normal));
-            let val = memory_map.read(addr);
-            let old_bit0 = val & 0x01;
-            let new_val = (val >> 1) | (old_bit0 << 7);
-            memory_map.write(addr, new_val);
-            cpu.registers.af.flags.set(Flag::C, old_bit0 != 0);
-            cpu.registers.af.flags.set(Flag::Z, new_val == 0);
+            memory_map.write(cpu.registers.read_u16(Register::HL(HLMode::Normal)), value)
         }
-        _ => panic!("Invalid target given to RRC instruction"),
-    };
+        _ => panic!("Invalid target given to CB instruction"),
+    }
+}
 
+/// Shared by the CB rotates, shifts and SWAP. `shift` gets the value and the
+/// old carry and returns the result and the new carry.
+fn shift_operation(
+    cpu: &mut Cpu,
+    target: OpTarget,
+    memory_map: &mut MemoryMap,
+    shift: fn(u8, bool) -> (u8, bool),
+) {
+    let value = cb_target_value(cpu, target, memory_map);
+    let (result, carry) = shift(value, cpu.registers.af.flags.get(Flag::C));
+    set_cb_target_value(cpu, target, result, memory_map);
+    cpu.registers.af.flags.set(Flag::Z, result == 0);
     cpu.registers.af.flags.set(Flag::N, false);
     cpu.registers.af.flags.set(Flag::H, false);
+    cpu.registers.af.flags.set(Flag::C, carry);
+}
+
+pub fn rlc_operation(cpu: &mut Cpu, target: OpTarget, memory_map: &mut MemoryMap) {
+    shift_operation(cpu, target, memory_map, |value, _| {
+        (value.rotate_left(1), value & 0x80 != 0)
+    });
+}
+
+pub fn rrc_operation(cpu: &mut Cpu, target: OpTarget, memory_map: &mut MemoryMap) {
+    shift_operation(cpu, target, memory_map, |value, _| {
+        (value.rotate_right(1), value & 0x01 != 0)
+    });
+}
+
+pub fn rl_operation(cpu: &mut Cpu, target: OpTarget, memory_map: &mut MemoryMap) {
+    shift_operation(cpu, target, memory_map, |value, carry| {
+        ((value << 1) | carry as u8, value & 0x80 != 0)
+    });
+}
+
+pub fn rr_operation(cpu: &mut Cpu, target: OpTarget, memory_map: &mut MemoryMap) {
+    shift_operation(cpu, target, memory_map, |value, carry| {
+        ((value >> 1) | (carry as u8) << 7, value & 0x01 != 0)
+    });
+}
+
+pub fn sla_operation(cpu: &mut Cpu, target: OpTarget, memory_map: &mut MemoryMap) {
+    shift_operation(cpu, target, memory_map, |value, _| {
+        (value << 1, value & 0x80 != 0)
+    });
+}
+
+pub fn sra_operation(cpu: &mut Cpu, target: OpTarget, memory_map: &mut MemoryMap) {
+    shift_operation(cpu, target, memory_map, |value, _| {
+        ((value >> 1) | (value & 0x80), value & 0x01 != 0)
+    });
+}
+
+pub fn swap_operation(cpu: &mut Cpu, target: OpTarget, memory_map: &mut MemoryMap) {
+    shift_operation(cpu, target, memory_map, |value, _| {
+        (value.rotate_left(4), false)
+    });
+}
+
+pub fn srl_operation(cpu: &mut Cpu, target: OpTarget, memory_map: &mut MemoryMap) {
+    shift_operation(cpu, target, memory_map, |value, _| {
+        (value >> 1, value & 0x01 != 0)
+    });
+}
+
+pub fn res_operation(cpu: &mut Cpu, target: OpTarget, value: u8, memory_map: &mut MemoryMap) {
+    if value > 7 {
+        panic!("Invalid value given for RES operation")
+    }
+    let result = cb_target_value(cpu, target, memory_map) & !(1 << value);
+    set_cb_target_value(cpu, target, result, memory_map);
+}
+
+pub fn set_operation(cpu: &mut Cpu, target: OpTarget, value: u8, memory_map: &mut MemoryMap) {
+    if value > 7 {
+        panic!("Invalid value given for SET operation")
+    }
+    let result = cb_target_value(cpu, target, memory_map) | (1 << value);
+    set_cb_target_value(cpu, target, result, memory_map);
 }
 
 pub fn rrca_operation(cpu: &mut Cpu) {
@@ -777,12 +874,12 @@ pub fn rlca_operation(cpu: &mut Cpu) {
     cpu.registers.af.flags.set(Flag::H, false);
 }
 
-pub fn jr_operation(cpu: &mut Cpu, condition: Condition, value: i8) {
+pub fn jr_operation(cpu: &mut Cpu, condition: Condition, value: i8) -> bool {
     match condition {
-        Condition::C if !cpu.registers.af.flags.get(Flag::C) => return,
-        Condition::NC if cpu.registers.af.flags.get(Flag::C) => return,
-        Condition::Z if !cpu.registers.af.flags.get(Flag::Z) => return,
-        Condition::NZ if cpu.registers.af.flags.get(Flag::Z) => return,
+        Condition::C if !cpu.registers.af.flags.get(Flag::C) => return false,
+        Condition::NC if cpu.registers.af.flags.get(Flag::C) => return false,
+        Condition::Z if !cpu.registers.af.flags.get(Flag::Z) => return false,
+        Condition::NZ if cpu.registers.af.flags.get(Flag::Z) => return false,
         _ => (),
     }
     cpu.registers.pc.programcounter = cpu
@@ -790,6 +887,7 @@ pub fn jr_operation(cpu: &mut Cpu, condition: Condition, value: i8) {
         .pc
         .programcounter
         .wrapping_add_signed(value as i16);
+    true
 }
 
 pub fn jp_operation(
@@ -798,12 +896,12 @@ pub fn jp_operation(
     target: OpTarget,
     value1: Option<u8>,
     value2: Option<u8>,
-) {
+) -> bool {
     match condition {
-        Condition::C if !cpu.registers.af.flags.get(Flag::C) => return,
-        Condition::NC if cpu.registers.af.flags.get(Flag::C) => return,
-        Condition::Z if !cpu.registers.af.flags.get(Flag::Z) => return,
-        Condition::NZ if cpu.registers.af.flags.get(Flag::Z) => return,
+        Condition::C if !cpu.registers.af.flags.get(Flag::C) => return false,
+        Condition::NC if cpu.registers.af.flags.get(Flag::C) => return false,
+        Condition::Z if !cpu.registers.af.flags.get(Flag::Z) => return false,
+        Condition::NZ if cpu.registers.af.flags.get(Flag::Z) => return false,
         _ => (),
     }
     let value: u16 = match target {
@@ -817,7 +915,8 @@ pub fn jp_operation(
         }
         _ => panic!("invalid target given to jp operation"),
     };
-    cpu.registers.write_u16(Register::PC, value)
+    cpu.registers.write_u16(Register::PC, value);
+    true
 }
 
 pub fn bit_operation(cpu: &mut Cpu, target: OpTarget, value: u8, memory_map: &mut MemoryMap) {
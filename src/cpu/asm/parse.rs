@@ -92,7 +92,7 @@ pub fn parse_opcode(operation: u8, cb_opcode: Option<u8>) -> Operation {
             Timing::Eight,
         ),
         0x0F => Operation::new(Opcode::RRCA, OpLength::One, Timing::Four),
-        0x10 => Operation::new(Opcode::STOP, OpLength::One, Timing::Four),
+        0x10 => Operation::new(Opcode::STOP, OpLength::Two, Timing::Four),
         0x11 => Operation::new(
             Opcode::LD(
                 OpTarget::Register(Register::DE),
@@ -1,5 +1,6 @@
 #[allow(clippy::upper_case_acronyms, non_camel_case_types)]
 #[allow(dead_code)]
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum Mode {
     DMG,
     GBC,
@@ -21,6 +22,23 @@ pub enum Timing {
     TwentyFour,
     Variable(Box<(Timing, Timing)>),
 }
+impl Timing {
+    /// T-cycles taken, `Variable` timings are (not taken, taken).
+    pub fn cycles(&self, branch_taken: bool) -> u32 {
+        match self {
+            Timing::Four => 4,
+            Timing::Eight => 8,
+            Timing::Twelve => 12,
+            Timing::Sixteen => 16,
+            Timing::Twenty => 20,
+            Timing::TwentyFour => 24,
+            Timing::Variable(timings) => match branch_taken {
+                true => timings.1.cycles(branch_taken),
+                false => timings.0.cycles(branch_taken),
+            },
+        }
+    }
+}
 
 pub enum Flag {
     Z,
@@ -1,4 +1,7 @@
 use crate::cpu::*;
+use crate::memory::Interrupt;
+
+const INTERRUPT_DISPATCH_CYCLES: u32 = 20;
 
 #[derive(Copy, Clone, Debug, PartialEq)]
 pub struct AccumulatorAndFlags {
@@ -86,16 +89,33 @@ pub struct ProgramCounter {
 #[derive(Copy, Clone, Debug, PartialEq)]
 pub struct InterruptMasterEnable {
     pub ime: bool,
-    pub has_waited: bool,
+    /// Set by EI, IME is only turned on once the instruction after it is done.
+    pub enable_pending: bool,
 }
 impl InterruptMasterEnable {
-    pub fn check_interrupts(cpu: &mut Cpu, memory: &mut MemoryMap) {
-        let interrupt_flags = memory.read(0xFFFF);
-        let joypad = interrupt_flags & 0b0001_0000;
-        let serial = interrupt_flags & 0b0000_1000;
-        let timer = interrupt_flags & 0b0000_0100;
-        let lcd = interrupt_flags & 0b0000_0010;
-        let vblank = interrupt_flags & 0b0000_0001;
+    /// Dispatches the highest priority interrupt that is both enabled and
+    /// requested: the current PC is pushed, the IF bit cleared and execution
+    /// continues at the interrupt vector. Returns the cycles spent.
+    pub fn check_interrupts(cpu: &mut Cpu, memory: &mut MemoryMap) -> u32 {
+        if !cpu.registers.ime.ime {
+            return 0;
+        }
+        let pending = memory.pending_interrupts();
+        let Some(interrupt) = Interrupt::PRIORITY
+            .into_iter()
+            .find(|interrupt| pending & interrupt.bit() != 0)
+        else {
+            return 0;
+        };
+        cpu.registers.ime.ime = false;
+        memory.io_registers.interrupt_flags.clear(interrupt);
+        let [pc_high_byte, pc_low_byte] = cpu.registers.read_u16(Register::PC).to_be_bytes();
+        cpu.registers.sp.stackpointer = cpu.registers.sp.stackpointer.wrapping_sub(1);
+        memory.write(cpu.registers.sp.stackpointer, pc_high_byte);
+        cpu.registers.sp.stackpointer = cpu.registers.sp.stackpointer.wrapping_sub(1);
+        memory.write(cpu.registers.sp.stackpointer, pc_low_byte);
+        cpu.registers.write_u16(Register::PC, interrupt.vector());
+        INTERRUPT_DISPATCH_CYCLES
     }
 }
 
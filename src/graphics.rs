@@ -1,5 +1,38 @@
-use crate::memory::{self, MemoryMap, SCX, SCY};
-#[derive(Copy, Clone, PartialEq, Eq)]
+use crate::Mode;
+use crate::memory::{Interrupt, LCDC, Memory, MemoryMap, SCX, SCY};
+use crate::savestate::{SaveState, SaveStateError, StateReader, StateWriter};
+
+const DOTS_PER_LINE: u16 = 456;
+const OAM_SCAN_DOTS: u16 = 80;
+const TRANSFER_END_DOT: u16 = OAM_SCAN_DOTS + 172;
+const VBLANK_START_LINE: u8 = 144;
+const LINES_PER_FRAME: u8 = 154;
+/// Added to an object's CGB palette number in `ScanLine::palettes` to keep
+/// it apart from the background palettes.
+const OBJECT_PALETTES: u8 = 8;
+
+/// The PPU mode as reported in the low bits of STAT.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum PpuMode {
+    HBlank,
+    VBlank,
+    OamScan,
+    Transfer,
+}
+impl PpuMode {
+    pub fn from_stat(stat: u8) -> Self {
+        match stat & 0b11 {
+            0 => PpuMode::HBlank,
+            1 => PpuMode::VBlank,
+            2 => PpuMode::OamScan,
+            _ => PpuMode::Transfer,
+        }
+    }
+}
+/// A DMG shade from lightest to darkest. Tile data holds colour ids in the
+/// same form, which the palette registers map to shades before they reach
+/// the screen.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub enum Color {
     C0,
     C1,
@@ -7,6 +40,15 @@ pub enum Color {
     C3,
 }
 impl Color {
+    pub fn from_bits(bits: u8) -> Option<Self> {
+        match bits {
+            0x00 => Some(Color::C0),
+            0x01 => Some(Color::C1),
+            0x02 => Some(Color::C2),
+            0x03 => Some(Color::C3),
+            _ => None,
+        }
+    }
     pub fn as_bits(self) -> u8 {
         match self {
             Color::C0 => 0x00,
@@ -17,6 +59,53 @@ impl Color {
     }
 }
 
+/// Colours used to turn the four shades into RGBA.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ColorScheme {
+    /// The green tint of the original Game Boy screen.
+    ClassicGreen,
+    Greyscale,
+    /// The Game Boy Pocket's black and white screen.
+    Pocket,
+    /// RGBA for each shade from lightest to darkest.
+    Custom([[u8; 4]; 4]),
+}
+impl ColorScheme {
+    pub fn rgba(&self, color: Color) -> [u8; 4] {
+        let colors = match self {
+            ColorScheme::ClassicGreen => [
+                [0x9B, 0xBC, 0x0F, 0xFF],
+                [0x8B, 0xAC, 0x0F, 0xFF],
+                [0x30, 0x62, 0x30, 0xFF],
+                [0x0F, 0x38, 0x0F, 0xFF],
+            ],
+            ColorScheme::Greyscale => [
+                [0xFF, 0xFF, 0xFF, 0xFF],
+                [0xAA, 0xAA, 0xAA, 0xFF],
+                [0x55, 0x55, 0x55, 0xFF],
+                [0x00, 0x00, 0x00, 0xFF],
+            ],
+            ColorScheme::Pocket => [
+                [0xC4, 0xCF, 0xA1, 0xFF],
+                [0x8B, 0x95, 0x6D, 0xFF],
+                [0x4D, 0x53, 0x3C, 0xFF],
+                [0x1F, 0x1F, 0x1F, 0xFF],
+            ],
+            ColorScheme::Custom(colors) => *colors,
+        };
+        colors[color.as_bits() as usize]
+    }
+}
+
+/// Expands a CGB RGB555 colour, red in the low bits, to RGBA.
+pub fn rgb555_to_rgba(color: u16) -> [u8; 4] {
+    let channel = |shift: u16| {
+        let value = ((color >> shift) & 0x1F) as u8;
+        (value << 3) | (value >> 2)
+    };
+    [channel(0), channel(5), channel(10), 0xFF]
+}
+
 pub struct BGWindow {
     enabled: bool,
     scrollx: u8,
@@ -81,7 +170,7 @@ impl TileLine {
     }
 }
 
-#[derive(PartialEq, Eq)]
+#[derive(Copy, Clone, PartialEq, Eq)]
 pub struct Tile {
     lines: [TileLine; 8],
 }
@@ -95,26 +184,127 @@ impl Tile {
         }
         Self { lines }
     }
+    /// Colour id of the pixel at column `x` of row `y`.
+    pub fn pixel(&self, x: u8, y: u8) -> Color {
+        self.lines[y as usize].line[x as usize]
+    }
 }
 
+#[derive(Copy, Clone, PartialEq, Eq)]
 enum ObjectSize {
     EightXEight,
     EightXSixteen,
 }
+impl ObjectSize {
+    fn from_lcdc(lcdc: LCDC) -> Self {
+        match lcdc.tall_objects() {
+            true => ObjectSize::EightXSixteen,
+            false => ObjectSize::EightXEight,
+        }
+    }
+    fn height(self) -> i16 {
+        match self {
+            ObjectSize::EightXEight => 8,
+            ObjectSize::EightXSixteen => 16,
+        }
+    }
+}
+
+/// An OAM entry. Y is stored plus 16 and X plus 8 so objects can sit partly
+/// off the top and left of the screen.
+#[derive(Copy, Clone)]
 struct Object {
-    size: ObjectSize,
-    tile1: Tile,
-    tile2: Option<Tile>,
+    y: u8,
+    x: u8,
+    tile: u8,
+    attributes: u8,
+}
+impl Object {
+    const BEHIND_BACKGROUND: u8 = 0b1000_0000;
+    const Y_FLIP: u8 = 0b0100_0000;
+    const X_FLIP: u8 = 0b0010_0000;
+    const PALETTE: u8 = 0b0001_0000;
+    const CGB_BANK: u8 = 0b0000_1000;
+    const CGB_PALETTE: u8 = 0b0000_0111;
+    fn new([y, x, tile, attributes]: [u8; 4]) -> Self {
+        Self {
+            y,
+            x,
+            tile,
+            attributes,
+        }
+    }
+    fn on_line(&self, size: ObjectSize, line: u8) -> bool {
+        let top = self.y as i16 - 16;
+        (top..top + size.height()).contains(&(line as i16))
+    }
+    /// Colour id at screen column `x`, or `None` where it is transparent.
+    fn pixel(&self, memory: &MemoryMap, size: ObjectSize, line: u8, x: u8) -> Option<Color> {
+        let mut column = x as i16 + 8 - self.x as i16;
+        if !(0..8).contains(&column) {
+            return None;
+        }
+        let mut row = line as i16 + 16 - self.y as i16;
+        if self.attributes & Self::Y_FLIP != 0 {
+            row = size.height() - 1 - row;
+        }
+        if self.attributes & Self::X_FLIP != 0 {
+            column = 7 - column;
+        }
+        // In 8x16 mode the top tile is always even and the bottom one odd.
+        let tile = match size {
+            ObjectSize::EightXEight => self.tile,
+            ObjectSize::EightXSixteen => self.tile & 0xFE,
+        };
+        let bank = match memory.mode() {
+            Mode::GBC if self.attributes & Self::CGB_BANK != 0 => 1,
+            _ => 0,
+        };
+        let color = memory.vram[bank]
+            .tiledata
+            .tile(tile as usize + row as usize / 8)
+            .pixel(column as u8, row as u8 % 8);
+        (color != Color::C0).then_some(color)
+    }
+}
+
+/// A background or window pixel with the CGB attributes of the map entry it
+/// came from. The attributes are always 0 on DMG.
+#[derive(Copy, Clone)]
+struct BackgroundPixel {
+    color: Color,
+    attributes: u8,
+}
+impl BackgroundPixel {
+    const PRIORITY: u8 = 0b1000_0000;
+    const Y_FLIP: u8 = 0b0100_0000;
+    const X_FLIP: u8 = 0b0010_0000;
+    const BANK: u8 = 0b0000_1000;
+    const PALETTE: u8 = 0b0000_0111;
+    const BLANK: Self = Self {
+        color: Color::C0,
+        attributes: 0,
+    };
 }
 
 #[derive(Copy, Clone)]
 pub struct ScanLine {
+    /// Shades on DMG. On CGB these are colour ids still to be looked up in
+    /// the palette named by `palettes`.
     pub pixels: [Color; 160],
+    /// CGB palette of each pixel, 0-7 for the background and 8-15 for
+    /// objects. Always 0 on DMG.
+    pub palettes: [u8; 160],
+    /// CGB RGB555 colour of each pixel, looked up as the line was drawn so
+    /// palette changes part way down the screen show. Always 0 on DMG.
+    pub colors: [u16; 160],
 }
 impl ScanLine {
     pub fn new() -> Self {
         Self {
             pixels: [Color::C0; 160],
+            palettes: [0; 160],
+            colors: [0; 160],
         }
     }
 }
@@ -127,12 +317,34 @@ impl Default for ScanLine {
 #[derive(Clone)]
 pub struct Display {
     pub lines: [ScanLine; 144],
+    /// Position within the current line, 0-455.
+    dot: u16,
+    /// The STAT interrupt is requested on the rising edge of its sources
+    /// ORed together, so it has to remember the previous state.
+    stat_line: bool,
+    /// Line of the window to draw next.
+    window_line: u8,
+    /// Set once LY has matched WY this frame.
+    window_triggered: bool,
+    /// Whether the lines were drawn with the CGB palettes.
+    cgb: bool,
+    /// Objects found by the OAM scan of the current line, the first
+    /// `object_count` of them in drawing priority.
+    objects: [Object; 10],
+    object_count: usize,
 }
 
 impl Display {
     pub fn new() -> Self {
         Self {
             lines: [ScanLine::new(); 144],
+            dot: 0,
+            stat_line: false,
+            window_line: 0,
+            window_triggered: false,
+            cgb: false,
+            objects: [Object::new([0; 4]); 10],
+            object_count: 0,
         }
     }
     #[inline(always)]
@@ -140,8 +352,235 @@ impl Display {
         debug_assert!(x <= 160 && y <= 144);
         self.lines[y].pixels[x] = color;
     }
-    pub fn update(&mut self, memory: &memory::MemoryMap) {}
-    fn load_tiles_from_vram(memory: &memory::MemoryMap) {}
+    /// Runs the PPU for `cycles` dots. Each line is 80 dots of OAM scan,
+    /// 172 of pixel transfer and HBlank for the rest of its 456. Lines 144-153
+    /// are VBlank.
+    pub fn update(&mut self, memory: &mut MemoryMap, cycles: u32) {
+        if !memory.io_registers.lcdcontrol.lcd_enabled() {
+            self.dot = 0;
+            self.stat_line = false;
+            self.window_line = 0;
+            self.window_triggered = false;
+            memory.io_registers.ly.line = 0;
+            Self::set_mode(memory, PpuMode::HBlank);
+            return;
+        }
+        for _ in 0..cycles {
+            self.tick(memory);
+        }
+    }
+    pub fn mode(memory: &MemoryMap) -> PpuMode {
+        PpuMode::from_stat(memory.io_registers.stat.stat)
+    }
+    fn set_mode(memory: &mut MemoryMap, mode: PpuMode) {
+        let stat = &mut memory.io_registers.stat.stat;
+        *stat = (*stat & !0b11) | mode as u8;
+    }
+    fn tick(&mut self, memory: &mut MemoryMap) {
+        self.dot += 1;
+        let line = memory.io_registers.ly.line;
+        match self.dot {
+            OAM_SCAN_DOTS if line < VBLANK_START_LINE => Self::set_mode(memory, PpuMode::Transfer),
+            TRANSFER_END_DOT if line < VBLANK_START_LINE => {
+                self.render_line(memory, line);
+                Self::set_mode(memory, PpuMode::HBlank);
+                memory.hblank_dma();
+            }
+            DOTS_PER_LINE => {
+                self.dot = 0;
+                let line = (line + 1) % LINES_PER_FRAME;
+                memory.io_registers.ly.line = line;
+                match line {
+                    VBLANK_START_LINE => {
+                        self.window_line = 0;
+                        self.window_triggered = false;
+                        Self::set_mode(memory, PpuMode::VBlank);
+                        memory
+                            .io_registers
+                            .interrupt_flags
+                            .request(Interrupt::VBlank);
+                    }
+                    0..VBLANK_START_LINE => Self::set_mode(memory, PpuMode::OamScan),
+                    _ => (),
+                }
+            }
+            _ => (),
+        }
+        self.update_stat(memory);
+    }
+    fn update_stat(&mut self, memory: &mut MemoryMap) {
+        let io = &mut memory.io_registers;
+        let coincidence = io.ly.line == io.lyc.compare;
+        io.stat.stat = (io.stat.stat & !0b100) | (coincidence as u8) << 2;
+        let source = |bit: u8| io.stat.stat & (1 << bit) != 0;
+        let stat_line = match PpuMode::from_stat(io.stat.stat) {
+            PpuMode::HBlank => source(3),
+            PpuMode::VBlank => source(4),
+            PpuMode::OamScan => source(5),
+            PpuMode::Transfer => false,
+        } || (coincidence && source(6));
+        if stat_line && !self.stat_line {
+            io.interrupt_flags.request(Interrupt::Stat);
+        }
+        self.stat_line = stat_line;
+    }
+    fn render_line(&mut self, memory: &MemoryMap, line: u8) {
+        let io = &memory.io_registers;
+        let lcdc = io.lcdcontrol;
+        let cgb = memory.mode() == Mode::GBC;
+        self.cgb = cgb;
+        if line == io.wy.window_y {
+            self.window_triggered = true;
+        }
+        // On CGB clearing LCDC.0 takes the background's priority away
+        // instead of blanking it.
+        let background_drawn = cgb || lcdc.bg_enabled();
+        // WX below 7 pushes the window's first columns off the left edge.
+        let window_left = io.wx.window_x as i16 - 7;
+        let window_visible =
+            background_drawn && lcdc.window_enabled() && self.window_triggered && window_left < 160;
+        let size = ObjectSize::from_lcdc(lcdc);
+        self.object_count = 0;
+        if lcdc.objects_enabled() {
+            self.scan_objects(memory, size, line);
+        }
+        let objects = &self.objects[..self.object_count];
+        let y = io.scy.scroll_y.wrapping_add(line);
+        for x in 0..160_u8 {
+            let window_x = x as i16 - window_left;
+            let background = match background_drawn {
+                true if window_visible && window_x >= 0 => Self::map_pixel(
+                    memory,
+                    lcdc,
+                    lcdc.window_tile_map(),
+                    window_x as u8,
+                    self.window_line,
+                ),
+                true => Self::map_pixel(
+                    memory,
+                    lcdc,
+                    lcdc.bg_tile_map(),
+                    io.scx.scroll_x.wrapping_add(x),
+                    y,
+                ),
+                false => BackgroundPixel::BLANK,
+            };
+            let object = objects.iter().find_map(|object| {
+                object
+                    .pixel(memory, size, line, x)
+                    .map(|color| (object, color))
+            });
+            let background_output = match cgb {
+                true => (
+                    background.color,
+                    background.attributes & BackgroundPixel::PALETTE,
+                ),
+                false => (io.bgp.shade(background.color), 0),
+            };
+            // Priority is decided on the colour id, before any palette is
+            // applied. Either the object or its map entry can ask for
+            // background colours 1-3 to be drawn on top.
+            let behind_background = |object: &Object| {
+                lcdc.bg_enabled()
+                    && background.color != Color::C0
+                    && (object.attributes & Object::BEHIND_BACKGROUND != 0
+                        || background.attributes & BackgroundPixel::PRIORITY != 0)
+            };
+            let (color, palette) = match object {
+                Some((object, _)) if behind_background(object) => background_output,
+                Some((object, color)) if cgb => (
+                    color,
+                    OBJECT_PALETTES + (object.attributes & Object::CGB_PALETTE),
+                ),
+                Some((object, color)) => match object.attributes & Object::PALETTE {
+                    0 => (io.obp0.shade(color), 0),
+                    _ => (io.obp1.shade(color), 0),
+                },
+                None => background_output,
+            };
+            let scan_line = &mut self.lines[line as usize];
+            scan_line.pixels[x as usize] = color;
+            scan_line.palettes[x as usize] = palette;
+            if cgb {
+                scan_line.colors[x as usize] = match palette {
+                    0..OBJECT_PALETTES => io.bcp.rgb555(palette, color),
+                    _ => io.ocp.rgb555(palette - OBJECT_PALETTES, color),
+                };
+            }
+        }
+        // The window has its own line counter that only moves on lines it
+        // was drawn on, so hiding it part way down resumes where it left off.
+        if window_visible {
+            self.window_line = self.window_line.wrapping_add(1);
+        }
+    }
+    /// Fills `objects` with the first ten in OAM that overlap `line`, in
+    /// drawing priority. On DMG the object further left wins and OAM order
+    /// breaks ties, on CGB only OAM order counts.
+    fn scan_objects(&mut self, memory: &MemoryMap, size: ObjectSize, line: u8) {
+        let found = (0..40)
+            .map(|index| Object::new(memory.oam().entry(index)))
+            .filter(|object| object.on_line(size, line))
+            .take(10);
+        for object in found {
+            let count = self.object_count;
+            let position = match memory.mode() {
+                Mode::DMG => self.objects[..count].partition_point(|other| other.x <= object.x),
+                Mode::GBC => count,
+            };
+            self.objects.copy_within(position..count, position + 1);
+            self.objects[position] = object;
+            self.object_count += 1;
+        }
+    }
+    /// The pixel at `x`, `y` in the 256x256 tile map at `map`. On CGB the
+    /// entry's attributes sit at the same address in VRAM bank 1.
+    fn map_pixel(memory: &MemoryMap, lcdc: LCDC, map: u16, x: u8, y: u8) -> BackgroundPixel {
+        let address = map + (y as u16 / 8) * 32 + x as u16 / 8;
+        let tile_id = memory.vram[0].read(address);
+        let attributes = match memory.mode() {
+            Mode::GBC => memory.vram[1].read(address),
+            Mode::DMG => 0,
+        };
+        let (mut column, mut row) = (x % 8, y % 8);
+        if attributes & BackgroundPixel::X_FLIP != 0 {
+            column = 7 - column;
+        }
+        if attributes & BackgroundPixel::Y_FLIP != 0 {
+            row = 7 - row;
+        }
+        let bank = (attributes & BackgroundPixel::BANK != 0) as usize;
+        let color = memory.vram[bank]
+            .tiledata
+            .get_tile(lcdc, tile_id)
+            .pixel(column, row);
+        BackgroundPixel { color, attributes }
+    }
+    /// The screen as 160x144 RGBA pixels, row by row from the top left.
+    /// `scheme` colours DMG shades. A CGB screen has colours of its own and
+    /// comes out the same as `cgb_rgba`.
+    pub fn rgba(&self, scheme: ColorScheme) -> Vec<u8> {
+        if self.cgb {
+            return self.cgb_rgba();
+        }
+        self.lines
+            .iter()
+            .flat_map(|line| line.pixels)
+            .flat_map(|pixel| scheme.rgba(pixel))
+            .collect()
+    }
+    /// A CGB screen as 160x144 RGB555 pixels, row by row from the top left.
+    pub fn rgb555(&self) -> Vec<u16> {
+        self.lines.iter().flat_map(|line| line.colors).collect()
+    }
+    /// A CGB screen as 160x144 RGBA pixels, row by row from the top left.
+    pub fn cgb_rgba(&self) -> Vec<u8> {
+        self.lines
+            .iter()
+            .flat_map(|line| line.colors)
+            .flat_map(rgb555_to_rgba)
+            .collect()
+    }
     pub fn test_pattern(&mut self) {
         let mut i = 0;
         for y in 0..144 {
@@ -179,3 +618,50 @@ impl Default for Display {
         Self::new()
     }
 }
+
+impl SaveState for Display {
+    fn save_state(&self, writer: &mut StateWriter) {
+        for line in &self.lines {
+            for pixel in line.pixels {
+                writer.write_u8(pixel.as_bits());
+            }
+            for palette in line.palettes {
+                writer.write_u8(palette);
+            }
+            for color in line.colors {
+                writer.write_u16(color);
+            }
+        }
+        writer.write_u16(self.dot);
+        writer.write_bool(self.stat_line);
+        writer.write_u8(self.window_line);
+        writer.write_bool(self.window_triggered);
+        writer.write_bool(self.cgb);
+    }
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), SaveStateError> {
+        for line in &mut self.lines {
+            for pixel in &mut line.pixels {
+                *pixel = Color::from_bits(reader.read_u8()?)
+                    .ok_or(SaveStateError::Corrupt("invalid pixel colour"))?;
+            }
+            for palette in &mut line.palettes {
+                *palette = reader.read_u8()?;
+                if *palette >= OBJECT_PALETTES * 2 {
+                    return Err(SaveStateError::Corrupt("pixel palette out of range"));
+                }
+            }
+            for color in &mut line.colors {
+                *color = reader.read_u16()? & 0x7FFF;
+            }
+        }
+        self.dot = reader.read_u16()?;
+        if self.dot >= DOTS_PER_LINE {
+            return Err(SaveStateError::Corrupt("PPU dot out of range"));
+        }
+        self.stat_line = reader.read_bool()?;
+        self.window_line = reader.read_u8()?;
+        self.window_triggered = reader.read_bool()?;
+        self.cgb = reader.read_bool()?;
+        Ok(())
+    }
+}
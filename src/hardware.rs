@@ -31,6 +31,14 @@ impl Hardware {
             joypad: Joypad::new(),
         }
     }
+    pub fn joypad(&self) -> &Joypad {
+        &self.joypad
+    }
+    /// Only updates the held buttons, `GameBoy::set_button` also brings P1
+    /// in line with them.
+    pub(crate) fn set_button(&mut self, button: Button, state: ButtonState) {
+        self.joypad.set(button, state);
+    }
 }
 
 impl Default for Hardware {
@@ -64,19 +72,33 @@ impl Joypad {
         }
     }
     pub fn set(&mut self, button: Button, state: ButtonState) {
-        let button = &mut match button {
-            Button::Up => self.up,
-            Button::Down => self.down,
-            Button::Left => self.left,
-            Button::Right => self.right,
-            Button::Start => self.start,
-            Button::Select => self.select,
-            Button::A => self.a,
-            Button::B => self.b,
+        let button = match button {
+            Button::Up => &mut self.up,
+            Button::Down => &mut self.down,
+            Button::Left => &mut self.left,
+            Button::Right => &mut self.right,
+            Button::Start => &mut self.start,
+            Button::Select => &mut self.select,
+            Button::A => &mut self.a,
+            Button::B => &mut self.b,
         };
         *button = match state {
             ButtonState::Up => false,
             ButtonState::Down => true,
         };
     }
+    /// P1 input lines for the d-pad, right, left, up and down from bit 0.
+    /// A line is low while its direction is held.
+    pub fn dpad_lines(&self) -> u8 {
+        Self::lines([self.right, self.left, self.up, self.down])
+    }
+    /// P1 input lines for A, B, select and start from bit 0.
+    pub fn button_lines(&self) -> u8 {
+        Self::lines([self.a, self.b, self.select, self.start])
+    }
+    fn lines(held: [bool; 4]) -> u8 {
+        (0..4)
+            .filter(|bit| held[*bit])
+            .fold(0x0F, |lines, bit| lines & !(1 << bit))
+    }
 }
@@ -1,14 +1,29 @@
 use std::{
+    io,
     sync::{Arc, Mutex},
-    time::Instant,
+    time::{Duration, Instant},
 };
 
-use crate::{cpu::Cpu, graphics::Display, hardware::Hardware, memory::MemoryMap};
+use crate::{
+    audio::{AudioError, AudioSource, Recording, StereoFrame},
+    cartridge::{Cartridge, SaveWriter},
+    cpu::Cpu,
+    graphics::Display,
+    hardware::Hardware,
+    memory::MemoryMap,
+    savestate::{SaveState, SaveStateError, StateReader, StateWriter},
+};
+
+pub use crate::cpu::Mode;
+pub use crate::hardware::{Button, ButtonState};
 
+pub mod audio;
+pub mod cartridge;
 mod cpu;
 pub mod graphics;
 mod hardware;
 mod memory;
+pub mod savestate;
 
 pub struct GameBoy {
     pub cpu: Cpu,
@@ -16,21 +31,162 @@ pub struct GameBoy {
     pub hardware: Hardware,
     pub display: Arc<Mutex<Display>>,
     pub timer: Instant,
+    /// T-cycles of the normal clock run since power on, for stepping the
+    /// rest of the hardware in lockstep with the CPU.
+    pub cycles: u64,
+    autosave: Option<Box<dyn SaveWriter>>,
 }
 impl GameBoy {
-    pub fn new(mode: crate::cpu::Mode) -> Self {
+    pub fn new(mode: Mode, cartridge: Option<Cartridge>) -> Self {
+        let mut memory = MemoryMap::with_mode(mode);
+        if let Some(cartridge) = cartridge {
+            memory.load_cartridge(cartridge);
+        }
         Self {
             cpu: Cpu::new(mode),
-            memory: MemoryMap::new(),
+            memory,
             hardware: Hardware::new(),
             display: Arc::new(Mutex::new(Display::new())),
             timer: Instant::now(),
+            cycles: 0,
+            autosave: None,
+        }
+    }
+    /// Runs a single instruction and returns the T-cycles it took at the
+    /// normal 4 MHz clock, which is half the CPU's in double speed mode.
+    pub fn step(&mut self) -> u32 {
+        // The CPU is held while VRAM DMA copies.
+        let cpu_cycles = match self.memory.take_vram_dma_stall() {
+            0 => self.cpu.execute_next_instruction(&mut self.memory),
+            stall => stall,
+        };
+        // DIV is held in reset while stopped.
+        if !self.cpu.stopped {
+            self.memory.tick_timer(cpu_cycles);
+        }
+        self.memory.tick_dma(cpu_cycles);
+        // The PPU and APU stay at the normal clock in double speed mode.
+        let cycles = match self.memory.double_speed() {
+            true => cpu_cycles / 2,
+            false => cpu_cycles,
+        };
+        self.memory.tick_apu(cycles);
+        self.memory.tick_rtc(cycles);
+        self.display
+            .lock()
+            .expect("failed to unlock display mutex")
+            .update(&mut self.memory, cycles);
+        self.cycles += cycles as u64;
+        cycles
+    }
+    pub fn cartridge(&self) -> Option<&Cartridge> {
+        self.memory.cartridge()
+    }
+    /// Whether a CGB game has switched the CPU to double speed. `step` counts
+    /// cycles of the normal clock either way, so a frame still takes 70224.
+    pub fn double_speed(&self) -> bool {
+        self.memory.double_speed()
+    }
+    /// Presses or releases a button. Pressing one the game has selected
+    /// through P1 requests the joypad interrupt and ends STOP.
+    pub fn set_button(&mut self, button: Button, state: ButtonState) {
+        self.hardware.set_button(button, state);
+        self.memory.update_joypad(self.hardware.joypad());
+    }
+    /// Sets how many audio frames are made per second of emulated time,
+    /// 48000 unless changed. Fails for rates outside 1 Hz to 4194304 Hz.
+    pub fn set_sample_rate(&mut self, sample_rate: u32) -> Result<(), AudioError> {
+        self.memory.io_registers.apu.set_sample_rate(sample_rate)
+    }
+    pub fn samples_available(&self) -> usize {
+        self.memory.io_registers.apu.samples_available()
+    }
+    /// Moves the oldest buffered audio into `frames` and returns how many
+    /// frames were written. Frames that aren't pulled in time are dropped
+    /// once the buffer fills up.
+    pub fn pull_samples(&mut self, frames: &mut [StereoFrame]) -> usize {
+        self.memory.io_registers.apu.pull_samples(frames)
+    }
+    /// Starts recording `length` of `source` at `sample_rate`, replacing any
+    /// earlier recording. It fills up as the machine is stepped and doesn't
+    /// need a frontend pulling samples. Fails for sample rates outside 1 Hz
+    /// to 4194304 Hz.
+    pub fn start_recording(
+        &mut self,
+        source: AudioSource,
+        sample_rate: u32,
+        length: Duration,
+    ) -> Result<(), AudioError> {
+        let recording = Recording::new(source, sample_rate, length)?;
+        self.memory.io_registers.apu.start_recording(recording);
+        Ok(())
+    }
+    pub fn recording(&self) -> Option<&Recording> {
+        self.memory.io_registers.apu.recording()
+    }
+    pub fn take_recording(&mut self) -> Option<Recording> {
+        self.memory.io_registers.apu.take_recording()
+    }
+    pub fn cartridge_mut(&mut self) -> Option<&mut Cartridge> {
+        self.memory.cartridge_mut()
+    }
+    /// Sets where battery backed saves go when `flush_save` finds the
+    /// cartridge RAM has changed.
+    pub fn set_autosave(&mut self, writer: impl SaveWriter + 'static) {
+        self.autosave = Some(Box::new(writer));
+    }
+    /// Writes the save to the autosave writer if the cartridge has a battery
+    /// and its RAM changed since the last flush. Returns whether it wrote.
+    pub fn flush_save(&mut self) -> io::Result<bool> {
+        let (Some(writer), Some(cartridge)) = (&mut self.autosave, self.memory.cartridge_mut())
+        else {
+            return Ok(false);
+        };
+        if !cartridge.has_battery() || !cartridge.save_dirty() {
+            return Ok(false);
         }
+        writer.write_save(&cartridge.export_save())?;
+        cartridge.mark_saved();
+        Ok(true)
+    }
+    /// Serializes the whole machine in the format described in `savestate`.
+    pub fn save_state(&self) -> Vec<u8> {
+        let mut writer = StateWriter::new();
+        writer.write_u64(self.cycles);
+        self.cpu.save_state(&mut writer);
+        self.memory.save_state(&mut writer);
+        self.display
+            .lock()
+            .expect("failed to unlock display mutex")
+            .save_state(&mut writer);
+        writer.finish()
+    }
+    /// Restores a state made by `save_state`. The machine is left untouched
+    /// if the state can't be loaded.
+    pub fn load_state(&mut self, state: &[u8]) -> Result<(), SaveStateError> {
+        let mut reader = StateReader::new(state)?;
+        let cycles = reader.read_u64()?;
+        let mut cpu = self.cpu;
+        let mut memory = self.memory.clone();
+        let mut display = self
+            .display
+            .lock()
+            .expect("failed to unlock display mutex")
+            .clone();
+        cpu.load_state(&mut reader)?;
+        memory.load_state(&mut reader)?;
+        display.load_state(&mut reader)?;
+        reader.finish()?;
+        self.cycles = cycles;
+        self.cpu = cpu;
+        self.memory = memory;
+        *self.display.lock().expect("failed to unlock display mutex") = display;
+        Ok(())
     }
 }
 impl Default for GameBoy {
     fn default() -> Self {
-        Self::new(crate::cpu::Mode::DMG)
+        Self::new(Mode::DMG, None)
     }
 }
 
@@ -38,6 +194,8 @@ impl Default for GameBoy {
 mod tests {
     use super::*;
     use crate::cpu::*;
+    use crate::graphics::{self, Color, ColorScheme, Tile};
+    use crate::hardware::*;
     use crate::memory::*;
 
     const ENTRY_POINT: u16 = 0x0100;
@@ -150,13 +308,13 @@ mod tests {
         LD  A,0x0001
         INC A
         INC A
-        LD  HL,0xFF00
+        LD  HL,0xFF80
         LD  (HL),0x01
         INC (HL)
         PANIC
         */
         let bytes = [
-            0xFA, 0x00, 0x01, 0x3C, 0x3C, 0x21, 0xFF, 0x00, 0x36, 0x01, 0x34, 0xDB,
+            0xFA, 0x00, 0x01, 0x3C, 0x3C, 0x21, 0xFF, 0x80, 0x36, 0x01, 0x34, 0xDB,
         ];
         let mut memory_index = ENTRY_POINT;
         for byte in bytes {
@@ -167,7 +325,7 @@ mod tests {
             cpu.execute_next_instruction(&mut memory);
         }
         assert!(cpu.registers.af.accumulator == 0x02);
-        assert!(memory.read(0xFF00) == 0x02);
+        assert!(memory.read(0xFF80) == 0x02);
     }
 
     #[test]
@@ -178,12 +336,12 @@ mod tests {
         /*
         LD  A,0x0002
         DEC A
-        LD  HL,0xFF00
+        LD  HL,0xFF80
         LD  (HL),0x02
         DEC (HL)
         HALT
         */
-        let bytes = [0x3E, 0x02, 0x3D, 0x21, 0xFF, 0x00, 0x36, 0x02, 0x35, 0xDB];
+        let bytes = [0x3E, 0x02, 0x3D, 0x21, 0xFF, 0x80, 0x36, 0x02, 0x35, 0xDB];
         for byte in bytes {
             memory.write(memory_index, byte);
             memory_index += 1;
@@ -192,7 +350,7 @@ mod tests {
             cpu.execute_next_instruction(&mut memory);
         }
         assert!(cpu.registers.af.accumulator == 0x01);
-        assert!(memory.read(0xFF00) == 0x01);
+        assert!(memory.read(0xFF80) == 0x01);
     }
 
     #[test]
@@ -333,4 +491,1321 @@ mod tests {
         }
         assert!(world == "Hello, world!\0");
     }
+
+    struct SharedSave(Arc<Mutex<Vec<u8>>>);
+    impl SaveWriter for SharedSave {
+        fn write_save(&mut self, save: &[u8]) -> io::Result<()> {
+            *self.0.lock().unwrap() = save.to_vec();
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_autosave_flushes_dirty_ram() {
+        let mut rom = vec![0_u8; 0x8000];
+        rom[0x0147] = 0x03;
+        rom[0x0149] = 0x02;
+        let mut gameboy = GameBoy::new(Mode::DMG, Some(Cartridge::from_bytes(rom).unwrap()));
+        let save = Arc::new(Mutex::new(Vec::new()));
+        gameboy.set_autosave(SharedSave(save.clone()));
+        assert!(!gameboy.flush_save().unwrap());
+        gameboy.memory.write(0x0000, 0x0A);
+        gameboy.memory.write(0xA010, 0x77);
+        // Exporting through the cartridge leaves the flush to happen.
+        assert!(gameboy.cartridge().unwrap().export_save()[0x10] == 0x77);
+        assert!(gameboy.flush_save().unwrap());
+        assert!(save.lock().unwrap().len() == 0x2000);
+        assert!(save.lock().unwrap()[0x10] == 0x77);
+        assert!(!gameboy.flush_save().unwrap());
+    }
+
+    fn test_program_gameboy() -> GameBoy {
+        let mut gameboy = GameBoy::default();
+        /*
+        LD  A,0x42
+        LD  HL,0xC000
+        LD  (HL),A
+        LD  B,0x01
+        PANIC
+        */
+        let bytes = [0x3E, 0x42, 0x21, 0xC0, 0x00, 0x77, 0x06, 0x01, 0xDB];
+        for (address, byte) in (ENTRY_POINT..).zip(bytes) {
+            gameboy.memory.write(address, byte);
+        }
+        gameboy
+    }
+
+    #[test]
+    fn test_save_state_round_trip() {
+        let mut gameboy = test_program_gameboy();
+        gameboy.cpu.execute_next_instruction(&mut gameboy.memory);
+        gameboy.cpu.execute_next_instruction(&mut gameboy.memory);
+        let state = gameboy.save_state();
+        while gameboy.cpu.is_running {
+            gameboy.cpu.execute_next_instruction(&mut gameboy.memory);
+        }
+        assert!(gameboy.memory.read(0xC000) == 0x42);
+
+        gameboy.load_state(&state).unwrap();
+        assert!(gameboy.cpu.is_running);
+        assert!(gameboy.cpu.registers.pc.programcounter == 0x0105);
+        assert!(gameboy.memory.read(0xC000) == 0x00);
+        let mut fresh = test_program_gameboy();
+        fresh.load_state(&state).unwrap();
+        assert!(fresh.cpu.registers == gameboy.cpu.registers);
+        assert!(fresh.memory == gameboy.memory);
+        assert!(fresh.save_state() == state);
+    }
+
+    #[test]
+    fn test_save_state_rejects_bad_input() {
+        let mut gameboy = test_program_gameboy();
+        let state = gameboy.save_state();
+
+        let mut newer = state.clone();
+        newer[8..12].copy_from_slice(&(savestate::STATE_VERSION + 1).to_le_bytes());
+        assert!(
+            gameboy.load_state(&newer)
+                == Err(SaveStateError::UnsupportedVersion(
+                    savestate::STATE_VERSION + 1
+                ))
+        );
+        assert!(gameboy.load_state(&state[..state.len() - 1]) == Err(SaveStateError::Truncated));
+        assert!(gameboy.load_state(&[0; 4]) == Err(SaveStateError::BadMagic));
+        let mut flipped = state.clone();
+        flipped[100] ^= 0x01;
+        assert!(gameboy.load_state(&flipped) == Err(SaveStateError::ChecksumMismatch));
+
+        let mut rom = vec![0_u8; 0x8000];
+        rom[0x0147] = 0x01;
+        let mut other = GameBoy::new(Mode::DMG, Some(Cartridge::from_bytes(rom).unwrap()));
+        assert!(other.load_state(&state) == Err(SaveStateError::CartridgeMismatch));
+    }
+
+    #[test]
+    fn test_rom_writes_select_banks() {
+        let mut rom = vec![0_u8; 0x10000];
+        rom[0x0147] = 0x01;
+        rom[0x0148] = 0x01;
+        rom[0x0150] = 0xAA;
+        rom[0x8000] = 0x02;
+        let mut gameboy = GameBoy::new(Mode::DMG, Some(Cartridge::from_bytes(rom).unwrap()));
+        gameboy.memory.write(0x0150, 0x00);
+        gameboy.memory.write(0x2100, 0x02);
+        assert!(gameboy.memory.read(0x0150) == 0xAA);
+        assert!(gameboy.memory.read(0x4000) == 0x02);
+    }
+
+    #[test]
+    fn test_halt_waits_for_interrupt() {
+        let mut memory = memory::MemoryMap::new();
+        let mut cpu = cpu::Cpu::new(cpu::Mode::DMG);
+        /*
+        HALT
+        INC B
+        PANIC
+        */
+        let bytes = [0x76, 0x04, 0xDB];
+        for (address, byte) in (ENTRY_POINT..).zip(bytes) {
+            memory.write(address, byte);
+        }
+        memory.write(0xFFFF, 0x04);
+        cpu.execute_next_instruction(&mut memory);
+        cpu.execute_next_instruction(&mut memory);
+        assert!(cpu.halted);
+        assert!(cpu.registers.pc.programcounter == ENTRY_POINT + 1);
+        memory.write(0xFF0F, 0x04);
+        cpu.execute_next_instruction(&mut memory);
+        assert!(!cpu.halted);
+        assert!(cpu.registers.bc.b == 0x01);
+    }
+
+    #[test]
+    fn test_halt_bug() {
+        let mut memory = memory::MemoryMap::new();
+        let mut cpu = cpu::Cpu::new(cpu::Mode::DMG);
+        /*
+        HALT
+        INC B
+        PANIC
+        */
+        let bytes = [0x76, 0x04, 0xDB];
+        for (address, byte) in (ENTRY_POINT..).zip(bytes) {
+            memory.write(address, byte);
+        }
+        memory.write(0xFFFF, 0x01);
+        memory.write(0xFF0F, 0x01);
+        while cpu.is_running {
+            cpu.execute_next_instruction(&mut memory);
+        }
+        assert!(!cpu.halted);
+        assert!(cpu.registers.bc.b == 0x02);
+    }
+
+    #[test]
+    fn test_stop_waits_for_joypad() {
+        let mut memory = memory::MemoryMap::new();
+        let mut cpu = cpu::Cpu::new(cpu::Mode::DMG);
+        /*
+        STOP
+        INC B
+        PANIC
+        */
+        let bytes = [0x10, 0x00, 0x04, 0xDB];
+        for (address, byte) in (ENTRY_POINT..).zip(bytes) {
+            memory.write(address, byte);
+        }
+        memory.write(0xFF04, 0x12);
+        cpu.execute_next_instruction(&mut memory);
+        cpu.execute_next_instruction(&mut memory);
+        assert!(cpu.stopped);
+        assert!(memory.read(0xFF04) == 0x00);
+        assert!(cpu.registers.pc.programcounter == ENTRY_POINT + 2);
+        // A held button only ends STOP once it is selected.
+        let mut joypad = Joypad::new();
+        joypad.set(Button::A, ButtonState::Down);
+        memory.write(0xFF00, 0x20);
+        memory.update_joypad(&joypad);
+        cpu.execute_next_instruction(&mut memory);
+        assert!(cpu.stopped);
+        memory.write(0xFF00, 0x10);
+        cpu.execute_next_instruction(&mut memory);
+        assert!(!cpu.stopped);
+        assert!(cpu.registers.bc.b == 0x01);
+    }
+
+    #[test]
+    fn test_joypad() {
+        let mut gameboy = GameBoy::default();
+        assert!(gameboy.memory.read(0xFF00) == 0xCF);
+        gameboy.memory.write(0xFF00, 0xFF);
+        assert!(gameboy.memory.read(0xFF00) == 0xFF);
+
+        // Nothing is selected, so pressing a button changes nothing.
+        gameboy.set_button(Button::Start, ButtonState::Down);
+        gameboy.set_button(Button::Left, ButtonState::Down);
+        assert!(gameboy.memory.read(0xFF00) == 0xFF);
+        assert!(gameboy.memory.read(0xFF0F) & Interrupt::Joypad.bit() == 0);
+
+        // Selecting the buttons brings start's line low.
+        gameboy.memory.write(0xFF00, 0x10);
+        assert!(gameboy.memory.read(0xFF00) == 0xD7);
+        assert!(gameboy.memory.read(0xFF0F) & Interrupt::Joypad.bit() != 0);
+        gameboy.memory.write(0xFF0F, 0x00);
+        gameboy.memory.write(0xFF00, 0x20);
+        assert!(gameboy.memory.read(0xFF00) == 0xED);
+        gameboy.memory.write(0xFF0F, 0x00);
+
+        // Only presses are edges, releases aren't.
+        gameboy.set_button(Button::Left, ButtonState::Up);
+        assert!(gameboy.memory.read(0xFF00) == 0xEF);
+        assert!(gameboy.memory.read(0xFF0F) & Interrupt::Joypad.bit() == 0);
+        gameboy.set_button(Button::Down, ButtonState::Down);
+        assert!(gameboy.memory.read(0xFF00) == 0xE7);
+        assert!(gameboy.memory.read(0xFF0F) & Interrupt::Joypad.bit() != 0);
+
+        // With both selected the lines are ANDed.
+        gameboy.memory.write(0xFF00, 0x00);
+        assert!(gameboy.memory.read(0xFF00) == 0xC7);
+        gameboy.set_button(Button::Down, ButtonState::Up);
+        gameboy.set_button(Button::Start, ButtonState::Up);
+        assert!(gameboy.memory.read(0xFF00) == 0xCF);
+    }
+
+    #[test]
+    fn test_stop_switches_speed() {
+        let mut memory = memory::MemoryMap::with_mode(Mode::GBC);
+        let mut cpu = cpu::Cpu::new(cpu::Mode::GBC);
+        let bytes = [0x10, 0x00, 0xDB];
+        for (address, byte) in (ENTRY_POINT..).zip(bytes) {
+            memory.write(address, byte);
+        }
+        memory.write(0xFF4D, 0x01);
+        assert!(memory.read(0xFF4D) == 0x7F);
+        cpu.execute_next_instruction(&mut memory);
+        assert!(!cpu.stopped);
+        assert!(memory.read(0xFF4D) == 0xFE);
+        assert!(cpu.registers.pc.programcounter == ENTRY_POINT + 2);
+
+        let mut memory = memory::MemoryMap::new();
+        memory.write(0xFF4D, 0x01);
+        assert!(memory.read(0xFF4D) == 0xFF);
+    }
+
+    #[test]
+    fn test_double_speed() {
+        let mut gameboy = GameBoy::new(Mode::GBC, None);
+        /*
+        LD   A,0x01
+        LDH  (0x4D),A
+        STOP
+        JR   -2
+        */
+        let bytes = [0x3E, 0x01, 0xE0, 0x4D, 0x10, 0x00, 0x18, 0xFE];
+        for (address, byte) in (ENTRY_POINT..).zip(bytes) {
+            gameboy.memory.write(address, byte);
+        }
+        gameboy.memory.write(0xFF40, 0x80);
+        for _ in 0..3 {
+            gameboy.step();
+        }
+        assert!(gameboy.double_speed());
+        assert!(gameboy.memory.read(0xFF04) == 0x00);
+        // JR takes 12 CPU cycles, which is 6 of the normal clock.
+        for _ in 0..256 {
+            assert!(gameboy.step() == 6);
+        }
+        // DIV counts 3072 CPU cycles while the PPU keeps to the normal clock.
+        assert!(gameboy.memory.read(0xFF04) == 12);
+        assert!(gameboy.memory.read(0xFF44) == (gameboy.cycles / 456) as u8);
+    }
+
+    #[test]
+    fn test_interrupt_dispatch() {
+        let mut memory = memory::MemoryMap::new();
+        let mut cpu = cpu::Cpu::new(cpu::Mode::DMG);
+        /*
+        EI
+        INC B
+        INC B
+        PANIC
+        */
+        let bytes = [0xFB, 0x04, 0x04, 0xDB];
+        for (address, byte) in (ENTRY_POINT..).zip(bytes) {
+            memory.write(address, byte);
+        }
+        /*
+        INC C
+        RETI
+        */
+        memory.write(0x0050, 0x0C);
+        memory.write(0x0051, 0xD9);
+        memory.write(0xFFFF, 0x04);
+        memory.write(0xFF0F, 0x04);
+        cpu.execute_next_instruction(&mut memory);
+        assert!(!cpu.registers.ime.ime);
+        cpu.execute_next_instruction(&mut memory);
+        assert!(cpu.registers.ime.ime);
+        assert!(cpu.registers.bc.b == 0x01);
+        cpu.execute_next_instruction(&mut memory);
+        assert!(cpu.registers.pc.programcounter == 0x0050);
+        assert!(cpu.registers.sp.stackpointer == 0xFFFC);
+        assert!(memory.read(0xFF0F) == 0xE0);
+        while cpu.is_running {
+            cpu.execute_next_instruction(&mut memory);
+        }
+        assert!(cpu.registers.bc.b == 0x02);
+        assert!(cpu.registers.bc.c == 0x14);
+        assert!(cpu.registers.sp.stackpointer == 0xFFFE);
+        assert!(cpu.registers.ime.ime);
+    }
+
+    #[test]
+    fn test_interrupt_priority() {
+        let mut memory = memory::MemoryMap::new();
+        let mut cpu = cpu::Cpu::new(cpu::Mode::DMG);
+        cpu.registers.ime.ime = true;
+        memory.write(0xFFFF, 0x1F);
+        memory.write(0xFF0F, 0x14);
+        cpu.execute_next_instruction(&mut memory);
+        assert!(cpu.registers.pc.programcounter == 0x0050);
+        assert!(memory.read(0xFF0F) == 0xF0);
+        assert!(!cpu.registers.ime.ime);
+    }
+
+    #[test]
+    fn test_di_cancels_ei() {
+        let mut memory = memory::MemoryMap::new();
+        let mut cpu = cpu::Cpu::new(cpu::Mode::DMG);
+        /*
+        EI
+        DI
+        NOP
+        PANIC
+        */
+        let bytes = [0xFB, 0xF3, 0x00, 0xDB];
+        for (address, byte) in (ENTRY_POINT..).zip(bytes) {
+            memory.write(address, byte);
+        }
+        memory.write(0xFFFF, 0x01);
+        memory.write(0xFF0F, 0x01);
+        while cpu.is_running {
+            cpu.execute_next_instruction(&mut memory);
+        }
+        assert!(!cpu.registers.ime.ime);
+        assert!(cpu.registers.pc.programcounter == ENTRY_POINT + 4);
+    }
+
+    #[test]
+    fn test_cb_opcodes() {
+        let flags = |result: u8, carry: bool| ((result == 0) as u8) << 7 | (carry as u8) << 4;
+        for opcode in 0..=0xFF_u8 {
+            let bit = (opcode >> 3) & 0x07;
+            for value in [0x00_u8, 0x01, 0x80, 0xA5, 0x5A, 0xFF] {
+                for carry in [false, true] {
+                    let old_flags = (carry as u8) << 4;
+                    let (expected, expected_flags) = match opcode >> 3 {
+                        0x00 => (
+                            value.rotate_left(1),
+                            flags(value.rotate_left(1), value & 0x80 != 0),
+                        ),
+                        0x01 => (
+                            value.rotate_right(1),
+                            flags(value.rotate_right(1), value & 0x01 != 0),
+                        ),
+                        0x02 => {
+                            let result = (value << 1) | carry as u8;
+                            (result, flags(result, value & 0x80 != 0))
+                        }
+                        0x03 => {
+                            let result = (value >> 1) | (carry as u8) << 7;
+                            (result, flags(result, value & 0x01 != 0))
+                        }
+                        0x04 => (value << 1, flags(value << 1, value & 0x80 != 0)),
+                        0x05 => {
+                            let result = (value >> 1) | (value & 0x80);
+                            (result, flags(result, value & 0x01 != 0))
+                        }
+                        0x06 => {
+                            let result = value.rotate_right(4);
+                            (result, flags(result, false))
+                        }
+                        0x07 => (value >> 1, flags(value >> 1, value & 0x01 != 0)),
+                        0x08..=0x0F => (
+                            value,
+                            (((value >> bit) & 0x01 == 0) as u8) << 7 | 0x20 | old_flags,
+                        ),
+                        0x10..=0x17 => (value & !(1 << bit), old_flags),
+                        _ => (value | (1 << bit), old_flags),
+                    };
+
+                    let mut memory = memory::MemoryMap::new();
+                    let mut cpu = cpu::Cpu::new(cpu::Mode::DMG);
+                    memory.write(ENTRY_POINT, 0xCB);
+                    memory.write(ENTRY_POINT + 1, opcode);
+                    cpu.registers.hl.h = 0xC0;
+                    cpu.registers.hl.l = 0x00;
+                    cpu.registers.af.flags.bits = old_flags;
+                    match opcode & 0x07 {
+                        0x00 => cpu.registers.bc.b = value,
+                        0x01 => cpu.registers.bc.c = value,
+                        0x02 => cpu.registers.de.d = value,
+                        0x03 => cpu.registers.de.e = value,
+                        0x04 => cpu.registers.hl.h = value,
+                        0x05 => cpu.registers.hl.l = value,
+                        0x06 => memory.write(0xC000, value),
+                        _ => cpu.registers.af.accumulator = value,
+                    }
+                    cpu.execute_next_instruction(&mut memory);
+                    let result = match opcode & 0x07 {
+                        0x00 => cpu.registers.bc.b,
+                        0x01 => cpu.registers.bc.c,
+                        0x02 => cpu.registers.de.d,
+                        0x03 => cpu.registers.de.e,
+                        0x04 => cpu.registers.hl.h,
+                        0x05 => cpu.registers.hl.l,
+                        0x06 => memory.read(0xC000),
+                        _ => cpu.registers.af.accumulator,
+                    };
+                    assert!(
+                        result == expected,
+                        "CB {opcode:02X} on {value:02X}: result {result:02X}, expected {expected:02X}"
+                    );
+                    assert!(
+                        cpu.registers.af.flags.bits == expected_flags,
+                        "CB {opcode:02X} on {value:02X}: flags {:08b}, expected {expected_flags:08b}",
+                        cpu.registers.af.flags.bits
+                    );
+                    assert!(cpu.registers.pc.programcounter == ENTRY_POINT + 2);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_cycle_counting() {
+        let mut gameboy = GameBoy::default();
+        /*
+        NOP
+        LD  B,0x01
+        JR  NZ,0x00 ; not taken, Z is set after boot
+        JR  Z,0x00
+        BIT 0,(HL)
+        RLC (HL)
+        PANIC
+        */
+        let bytes = [
+            0x00, 0x06, 0x01, 0x20, 0x00, 0x28, 0x00, 0xCB, 0x46, 0xCB, 0x06, 0xDB,
+        ];
+        for (address, byte) in (ENTRY_POINT..).zip(bytes) {
+            gameboy.memory.write(address, byte);
+        }
+        for expected in [4, 8, 8, 12, 12, 16, 4] {
+            assert!(gameboy.step() == expected);
+        }
+        assert!(!gameboy.cpu.is_running);
+        assert!(gameboy.cycles == 64);
+    }
+
+    #[test]
+    fn test_interrupt_and_halt_cycles() {
+        let mut gameboy = GameBoy::default();
+        // HALT
+        gameboy.memory.write(ENTRY_POINT, 0x76);
+        gameboy.memory.write(0xFFFF, 0x01);
+        gameboy.cpu.registers.ime.ime = true;
+        assert!(gameboy.step() == 4);
+        assert!(gameboy.step() == 4);
+        gameboy.memory.write(0xFF0F, 0x01);
+        assert!(gameboy.step() == 20);
+        assert!(gameboy.cpu.registers.pc.programcounter == 0x0040);
+        assert!(gameboy.cycles == 28);
+    }
+
+    #[test]
+    fn test_divider() {
+        let mut memory = memory::MemoryMap::new();
+        memory.tick_timer(255);
+        assert!(memory.read(0xFF04) == 0x00);
+        memory.tick_timer(4);
+        assert!(memory.read(0xFF04) == 0x01);
+        memory.write(0xFF04, 0x42);
+        assert!(memory.read(0xFF04) == 0x00);
+    }
+
+    #[test]
+    fn test_timer_frequencies() {
+        for (control, period) in [(0x04, 1024), (0x05, 16), (0x06, 64), (0x07, 256)] {
+            let mut memory = memory::MemoryMap::new();
+            memory.write(0xFF07, control);
+            memory.tick_timer(period * 3);
+            assert!(memory.read(0xFF05) == 0x03);
+        }
+        let mut memory = memory::MemoryMap::new();
+        memory.write(0xFF07, 0x01);
+        memory.tick_timer(1024);
+        assert!(memory.read(0xFF05) == 0x00);
+    }
+
+    #[test]
+    fn test_timer_overflow_reload() {
+        let mut memory = memory::MemoryMap::new();
+        memory.write(0xFF05, 0xFF);
+        memory.write(0xFF06, 0x20);
+        memory.write(0xFF07, 0x05);
+        memory.tick_timer(16);
+        assert!(memory.read(0xFF05) == 0x00);
+        assert!(memory.read(0xFF0F) & 0x04 == 0);
+        memory.tick_timer(4);
+        assert!(memory.read(0xFF05) == 0x20);
+        assert!(memory.read(0xFF0F) & 0x04 != 0);
+
+        // Writing TIMA in the delay cancels the reload and the interrupt.
+        let mut memory = memory::MemoryMap::new();
+        memory.write(0xFF05, 0xFF);
+        memory.write(0xFF06, 0x20);
+        memory.write(0xFF07, 0x05);
+        memory.tick_timer(16);
+        memory.write(0xFF05, 0x10);
+        memory.tick_timer(4);
+        assert!(memory.read(0xFF05) == 0x10);
+        assert!(memory.read(0xFF0F) & 0x04 == 0);
+    }
+
+    #[test]
+    fn test_timer_falling_edge() {
+        let mut memory = memory::MemoryMap::new();
+        memory.write(0xFF07, 0x05);
+        memory.tick_timer(8);
+        memory.write(0xFF04, 0x00);
+        assert!(memory.read(0xFF05) == 0x01);
+        memory.tick_timer(8);
+        memory.write(0xFF07, 0x00);
+        assert!(memory.read(0xFF05) == 0x02);
+    }
+
+    #[test]
+    fn test_timer_interrupt_wakes_halt() {
+        let mut gameboy = GameBoy::default();
+        /*
+        HALT
+        PANIC
+        */
+        gameboy.memory.write(ENTRY_POINT, 0x76);
+        gameboy.memory.write(ENTRY_POINT + 1, 0xDB);
+        gameboy.memory.write(0xFFFF, 0x04);
+        gameboy.memory.write(0xFF05, 0xFE);
+        gameboy.memory.write(0xFF07, 0x05);
+        while gameboy.cpu.is_running {
+            gameboy.step();
+        }
+        assert!(gameboy.memory.read(0xFF0F) & 0x04 != 0);
+        assert!(gameboy.cycles >= 32);
+    }
+
+    #[test]
+    fn test_ppu_modes() {
+        let mut memory = memory::MemoryMap::new();
+        let mut display = Display::new();
+        memory.write(0xFF40, 0x80);
+        display.update(&mut memory, 456);
+        assert!(memory.read(0xFF44) == 0x01);
+        assert!(Display::mode(&memory) == graphics::PpuMode::OamScan);
+        display.update(&mut memory, 80);
+        assert!(Display::mode(&memory) == graphics::PpuMode::Transfer);
+        display.update(&mut memory, 172);
+        assert!(Display::mode(&memory) == graphics::PpuMode::HBlank);
+        assert!(memory.read(0xFF41) & 0x03 == 0x00);
+        memory.write(0xFF40, 0x00);
+        display.update(&mut memory, 4);
+        assert!(memory.read(0xFF44) == 0x00);
+    }
+
+    #[test]
+    fn test_ppu_vblank() {
+        let mut memory = memory::MemoryMap::new();
+        let mut display = Display::new();
+        memory.write(0xFF40, 0x80);
+        display.update(&mut memory, 456 * 144 - 1);
+        assert!(memory.read(0xFF0F) & 0x01 == 0);
+        display.update(&mut memory, 1);
+        assert!(memory.read(0xFF44) == 144);
+        assert!(memory.read(0xFF41) & 0x03 == 0x01);
+        assert!(memory.read(0xFF0F) & 0x01 != 0);
+        display.update(&mut memory, 456 * 10);
+        assert!(memory.read(0xFF44) == 0);
+        assert!(Display::mode(&memory) == graphics::PpuMode::OamScan);
+    }
+
+    #[test]
+    fn test_ppu_stat_interrupts() {
+        let mut memory = memory::MemoryMap::new();
+        let mut display = Display::new();
+        memory.write(0xFF40, 0x80);
+        memory.write(0xFF45, 0x02);
+        memory.write(0xFF41, 0x40);
+        display.update(&mut memory, 456 * 2 - 1);
+        assert!(memory.read(0xFF0F) & 0x02 == 0);
+        assert!(memory.read(0xFF41) & 0x04 == 0);
+        display.update(&mut memory, 1);
+        assert!(memory.read(0xFF0F) & 0x02 != 0);
+        assert!(memory.read(0xFF41) == 0xC6);
+
+        memory.write(0xFF0F, 0x00);
+        memory.write(0xFF41, 0x08);
+        display.update(&mut memory, 251);
+        assert!(memory.read(0xFF0F) & 0x02 == 0);
+        display.update(&mut memory, 1);
+        assert!(memory.read(0xFF0F) & 0x02 != 0);
+    }
+
+    #[test]
+    fn test_ppu_background() {
+        let mut memory = memory::MemoryMap::new();
+        let mut display = Display::new();
+        // Tile 1 at 0x8010 is solid colour 1, tile 0x80 at 0x8800 solid colour 2.
+        for row in 0..8 {
+            memory.write(0x8010 + row * 2, 0xFF);
+            memory.write(0x8800 + row * 2 + 1, 0xFF);
+        }
+        memory.write(0x9800, 0x01);
+        memory.write(0x9801, 0x80);
+        memory.write(0x9C00, 0x80);
+        memory.write(0xFF47, 0xE4);
+        memory.write(0xFF40, 0x91);
+        display.update(&mut memory, 456);
+        assert!(display.lines[0].pixels[0..8] == [Color::C1; 8]);
+        assert!(display.lines[0].pixels[8..16] == [Color::C2; 8]);
+        assert!(display.lines[0].pixels[16] == Color::C0);
+
+        // Signed tile ids from the 0x9C00 map, scrolled by 4 pixels.
+        memory.write(0xFF40, 0x00);
+        display.update(&mut memory, 4);
+        memory.write(0xFF40, 0x89);
+        memory.write(0xFF43, 0x04);
+        display.update(&mut memory, 456);
+        assert!(display.lines[0].pixels[0..4] == [Color::C2; 4]);
+        assert!(display.lines[0].pixels[4] == Color::C0);
+    }
+
+    // The example tile from the Pan Docs and its colour ids row by row.
+    const TILE_BYTES: [u8; 16] = [
+        0x3C, 0x7E, 0x42, 0x42, 0x42, 0x42, 0x42, 0x42, 0x7E, 0x5E, 0x7E, 0x0A, 0x7C, 0x56, 0x38,
+        0x7C,
+    ];
+    const TILE_PIXELS: [[u8; 8]; 8] = [
+        [0, 2, 3, 3, 3, 3, 2, 0],
+        [0, 3, 0, 0, 0, 0, 3, 0],
+        [0, 3, 0, 0, 0, 0, 3, 0],
+        [0, 3, 0, 0, 0, 0, 3, 0],
+        [0, 3, 1, 3, 3, 3, 3, 0],
+        [0, 1, 1, 1, 3, 1, 3, 0],
+        [0, 3, 1, 3, 1, 3, 2, 0],
+        [0, 2, 3, 3, 3, 2, 0, 0],
+    ];
+
+    fn tile_pixels(tile: Tile) -> [[u8; 8]; 8] {
+        let mut pixels = [[0; 8]; 8];
+        for (y, row) in pixels.iter_mut().enumerate() {
+            for (x, pixel) in row.iter_mut().enumerate() {
+                *pixel = tile.pixel(x as u8, y as u8).as_bits();
+            }
+        }
+        pixels
+    }
+
+    #[test]
+    fn test_tile_data_addressing() {
+        let mut memory = memory::MemoryMap::new();
+        let signed = memory.io_registers.lcdcontrol;
+        memory.write(0xFF40, 0x10);
+        let unsigned = memory.io_registers.lcdcontrol;
+        for (address, byte) in (0x8010..).zip(TILE_BYTES) {
+            memory.write(address, byte);
+        }
+        for (address, byte) in (0x8FF0..).zip(TILE_BYTES) {
+            memory.write(address, byte);
+        }
+        for (address, byte) in (0x9020..).zip(TILE_BYTES) {
+            memory.write(address, byte);
+        }
+        let tiles = &memory.vram[0].tiledata;
+        assert!(tile_pixels(tiles.get_tile(unsigned, 0x01)) == TILE_PIXELS);
+        assert!(tile_pixels(tiles.get_tile(signed, 0x01)) == [[0; 8]; 8]);
+        // 0x8800-0x8FFF is shared by both modes.
+        assert!(tile_pixels(tiles.get_tile(unsigned, 0xFF)) == TILE_PIXELS);
+        assert!(tile_pixels(tiles.get_tile(signed, 0xFF)) == TILE_PIXELS);
+        // Signed ids 0-127 come from 0x9000, out of reach of unsigned ids.
+        assert!(tile_pixels(tiles.get_tile(signed, 0x02)) == TILE_PIXELS);
+        assert!(tile_pixels(tiles.get_tile(unsigned, 0x02)) == [[0; 8]; 8]);
+        assert!(tile_pixels(tiles.tile(0x102)) == TILE_PIXELS);
+    }
+
+    #[test]
+    fn test_tile_cache() {
+        let mut memory = memory::MemoryMap::new();
+        memory.write(0xFF40, 0x10);
+        let lcdc = memory.io_registers.lcdcontrol;
+        for (address, byte) in (0x8000..).zip(TILE_BYTES) {
+            memory.write(address, byte);
+        }
+        assert!(tile_pixels(memory.vram[0].tiledata.get_tile(lcdc, 0)) == TILE_PIXELS);
+        memory.write(0x800F, 0x00);
+        let mut pixels = TILE_PIXELS;
+        pixels[7] = [0, 0, 1, 1, 1, 0, 0, 0];
+        assert!(tile_pixels(memory.vram[0].tiledata.get_tile(lcdc, 0)) == pixels);
+
+        // Loading a state brings the decoded tiles back in line with VRAM.
+        let mut state = StateWriter::new();
+        memory.save_state(&mut state);
+        let state = state.finish();
+        let mut loaded = memory::MemoryMap::new();
+        let mut reader = StateReader::new(&state).unwrap();
+        loaded.load_state(&mut reader).unwrap();
+        assert!(tile_pixels(loaded.vram[0].tiledata.get_tile(lcdc, 0)) == pixels);
+    }
+
+    #[test]
+    fn test_background_palette() {
+        let mut memory = memory::MemoryMap::new();
+        let mut display = Display::new();
+        assert!(memory.read(0xFF47) == 0xFC);
+        // Tiles 1-3 are solid colours 1-3, drawn left to right after tile 0.
+        for row in 0..8 {
+            memory.write(0x8010 + row * 2, 0xFF);
+            memory.write(0x8020 + row * 2 + 1, 0xFF);
+            memory.write(0x8030 + row * 2, 0xFF);
+            memory.write(0x8030 + row * 2 + 1, 0xFF);
+        }
+        for tile in 1..4 {
+            memory.write(0x9800 + tile, tile as u8);
+        }
+        memory.write(0xFF47, 0x1B);
+        memory.write(0xFF40, 0x91);
+        display.update(&mut memory, 456);
+        let shades: Vec<Color> = (0..4)
+            .map(|tile| display.lines[0].pixels[tile * 8])
+            .collect();
+        assert!(shades == [Color::C3, Color::C2, Color::C1, Color::C0]);
+
+        let rgba = display.rgba(ColorScheme::Greyscale);
+        assert!(rgba.len() == 160 * 144 * 4);
+        assert!(rgba[..4] == [0x00, 0x00, 0x00, 0xFF]);
+        assert!(rgba[8 * 4..9 * 4] == [0x55, 0x55, 0x55, 0xFF]);
+        // Only the first line has been drawn.
+        assert!(rgba[160 * 4..161 * 4] == [0xFF, 0xFF, 0xFF, 0xFF]);
+        let custom = [
+            [1, 2, 3, 4],
+            [5, 6, 7, 8],
+            [9, 10, 11, 12],
+            [13, 14, 15, 16],
+        ];
+        let rgba = display.rgba(ColorScheme::Custom(custom));
+        assert!(rgba[24 * 4..25 * 4] == custom[0]);
+        assert!(display.rgba(ColorScheme::ClassicGreen)[..4] == [0x0F, 0x38, 0x0F, 0xFF]);
+        assert!(display.rgba(ColorScheme::Pocket)[..4] == [0x1F, 0x1F, 0x1F, 0xFF]);
+    }
+
+    fn window_test_memory() -> memory::MemoryMap {
+        let mut memory = memory::MemoryMap::new();
+        // Tile 1 is colour 1 on its first row, colour 2 on the second and
+        // colour 3 below that.
+        memory.write(0x8010, 0xFF);
+        memory.write(0x8013, 0xFF);
+        for row in 2..8 {
+            memory.write(0x8010 + row * 2, 0xFF);
+            memory.write(0x8010 + row * 2 + 1, 0xFF);
+        }
+        memory.write(0x9C00, 0x01);
+        memory.write(0xFF47, 0xE4);
+        memory
+    }
+
+    #[test]
+    fn test_window() {
+        let mut memory = window_test_memory();
+        let mut display = Display::new();
+        memory.write(0xFF4A, 0x01);
+        memory.write(0xFF4B, 0x07 + 80);
+        memory.write(0xFF40, 0xF1);
+        display.update(&mut memory, 456 * 2);
+        assert!(display.lines[0].pixels == [Color::C0; 160]);
+        assert!(display.lines[1].pixels[..80] == [Color::C0; 80]);
+        assert!(display.lines[1].pixels[80..88] == [Color::C1; 8]);
+        assert!(display.lines[1].pixels[88] == Color::C0);
+
+        // Without LCDC.0 neither layer is drawn.
+        memory.write(0xFF40, 0xF0);
+        display.update(&mut memory, 456);
+        assert!(display.lines[2].pixels == [Color::C0; 160]);
+    }
+
+    #[test]
+    fn test_window_line_counter() {
+        let mut memory = window_test_memory();
+        let mut display = Display::new();
+        memory.write(0xFF4B, 0x07);
+        memory.write(0xFF40, 0xF1);
+        display.update(&mut memory, 456);
+        memory.write(0xFF40, 0xD1);
+        display.update(&mut memory, 456 * 2);
+        memory.write(0xFF40, 0xF1);
+        display.update(&mut memory, 456);
+        assert!(display.lines[0].pixels[0] == Color::C1);
+        assert!(display.lines[1].pixels[0] == Color::C0);
+        assert!(display.lines[2].pixels[0] == Color::C0);
+        assert!(display.lines[3].pixels[0] == Color::C2);
+    }
+
+    #[test]
+    fn test_window_left_edge() {
+        let mut memory = window_test_memory();
+        let mut display = Display::new();
+        memory.write(0xFF4B, 0x03);
+        memory.write(0xFF40, 0xF1);
+        display.update(&mut memory, 456);
+        assert!(display.lines[0].pixels[..4] == [Color::C1; 4]);
+        assert!(display.lines[0].pixels[4] == Color::C0);
+
+        memory.write(0xFF4B, 0xA7);
+        display.update(&mut memory, 456);
+        assert!(display.lines[1].pixels == [Color::C0; 160]);
+    }
+
+    fn object_test_memory() -> memory::MemoryMap {
+        let mut memory = memory::MemoryMap::new();
+        // Tile 2 has a single colour 1 pixel in its top left corner and is
+        // colour 3 below that, tile 3 is solid colour 2.
+        memory.write(0x8020, 0x80);
+        for row in 1..8 {
+            memory.write(0x8020 + row * 2, 0xFF);
+            memory.write(0x8020 + row * 2 + 1, 0xFF);
+        }
+        for row in 0..8 {
+            memory.write(0x8030 + row * 2 + 1, 0xFF);
+        }
+        memory.write(0xFF47, 0xE4);
+        memory.write(0xFF48, 0xE4);
+        memory.write(0xFF49, 0x1B);
+        memory.write(0xFF40, 0x83);
+        memory
+    }
+
+    fn write_object(memory: &mut memory::MemoryMap, index: u16, bytes: [u8; 4]) {
+        for (address, byte) in (0xFE00 + index * 4..).zip(bytes) {
+            memory.write(address, byte);
+        }
+    }
+
+    #[test]
+    fn test_objects() {
+        let mut memory = object_test_memory();
+        let mut display = Display::new();
+        write_object(&mut memory, 0, [16, 8, 0x02, 0x00]);
+        write_object(&mut memory, 1, [16, 24, 0x02, 0x20]);
+        write_object(&mut memory, 2, [16, 40, 0x02, 0x40]);
+        write_object(&mut memory, 3, [16, 56, 0x02, 0x10]);
+        display.update(&mut memory, 456);
+        let line = display.lines[0].pixels;
+        assert!(line[0] == Color::C1);
+        assert!(line[1..8] == [Color::C0; 7]);
+        assert!(line[16..23] == [Color::C0; 7]);
+        assert!(line[23] == Color::C1);
+        assert!(line[32..40] == [Color::C3; 8]);
+        assert!(line[48] == Color::C2);
+
+        memory.write(0xFF40, 0x81);
+        display.update(&mut memory, 456);
+        assert!(display.lines[1].pixels == [Color::C0; 160]);
+    }
+
+    #[test]
+    fn test_object_background_priority() {
+        let mut memory = object_test_memory();
+        let mut display = Display::new();
+        // Background tile 0x80 at 0x8800 is solid colour 1 in the left column.
+        for row in 0..8 {
+            memory.write(0x8800 + row * 2, 0xF0);
+        }
+        memory.write(0x9800, 0x80);
+        write_object(&mut memory, 0, [16, 8, 0x02, 0x80]);
+        display.update(&mut memory, 456 * 2);
+        assert!(display.lines[1].pixels[..4] == [Color::C1; 4]);
+        assert!(display.lines[1].pixels[4..8] == [Color::C3; 4]);
+    }
+
+    #[test]
+    fn test_object_limit_and_ordering() {
+        let mut memory = object_test_memory();
+        let mut display = Display::new();
+        for index in 0..11 {
+            write_object(&mut memory, index, [16, 8 + 8 * index as u8, 0x02, 0x00]);
+        }
+        display.update(&mut memory, 456);
+        for index in 0..10 {
+            assert!(display.lines[0].pixels[8 * index] == Color::C1);
+        }
+        assert!(display.lines[0].pixels[80] == Color::C0);
+
+        // The object further left is drawn on top, OAM order breaks ties.
+        let mut memory = object_test_memory();
+        write_object(&mut memory, 0, [15, 12, 0x02, 0x00]);
+        write_object(&mut memory, 1, [15, 8, 0x03, 0x00]);
+        write_object(&mut memory, 2, [15, 40, 0x02, 0x00]);
+        write_object(&mut memory, 3, [15, 40, 0x03, 0x00]);
+        display.update(&mut memory, 456);
+        assert!(display.lines[0].pixels[..8] == [Color::C2; 8]);
+        assert!(display.lines[0].pixels[8..12] == [Color::C3; 4]);
+        assert!(display.lines[0].pixels[32..40] == [Color::C3; 8]);
+    }
+
+    #[test]
+    fn test_tall_objects() {
+        let mut memory = object_test_memory();
+        let mut display = Display::new();
+        memory.write(0xFF40, 0x87);
+        write_object(&mut memory, 0, [16, 8, 0x03, 0x00]);
+        write_object(&mut memory, 1, [16, 16, 0x03, 0x40]);
+        display.update(&mut memory, 456 * 9);
+        assert!(display.lines[0].pixels[0] == Color::C1);
+        assert!(display.lines[8].pixels[..8] == [Color::C2; 8]);
+        assert!(display.lines[0].pixels[8..16] == [Color::C2; 8]);
+        assert!(display.lines[8].pixels[8..16] == [Color::C3; 8]);
+    }
+
+    #[test]
+    fn test_vram_banks() {
+        let mut memory = memory::MemoryMap::new();
+        memory.write(0xFF4F, 0x01);
+        assert!(memory.read(0xFF4F) == 0xFF);
+        memory.write(0x8000, 0x12);
+        assert!(memory.vram[0].read(0x8000) == 0x12);
+        assert!(memory.vram[1].read(0x8000) == 0x00);
+
+        let mut memory = memory::MemoryMap::with_mode(Mode::GBC);
+        assert!(memory.read(0xFF4F) == 0xFE);
+        memory.write(0x8000, 0x12);
+        memory.write(0xFF4F, 0x01);
+        assert!(memory.read(0xFF4F) == 0xFF);
+        assert!(memory.read(0x8000) == 0x00);
+        memory.write(0x8000, 0x34);
+        memory.write(0x9800, 0x56);
+        assert!(memory.vram[0].read(0x8000) == 0x12);
+        assert!(memory.vram[1].read(0x8000) == 0x34);
+        assert!(memory.vram[0].read(0x9800) == 0x00);
+
+        let mut state = StateWriter::new();
+        memory.save_state(&mut state);
+        let state = state.finish();
+        let mut loaded = memory::MemoryMap::with_mode(Mode::GBC);
+        let mut reader = StateReader::new(&state).unwrap();
+        loaded.load_state(&mut reader).unwrap();
+        assert!(loaded.read(0xFF4F) == 0xFF);
+        assert!(loaded.read(0x8000) == 0x34);
+        assert!(loaded.read(0x9800) == 0x56);
+        loaded.write(0xFF4F, 0x00);
+        assert!(loaded.read(0x8000) == 0x12);
+    }
+
+    #[test]
+    fn test_cgb_map_attributes() {
+        let mut memory = memory::MemoryMap::with_mode(Mode::GBC);
+        let mut display = Display::new();
+        // Tile 1 in bank 1 is colour 3 in the left half of its bottom row,
+        // the same tile in bank 0 is blank.
+        memory.vram[1].write(0x801E, 0xF0);
+        memory.vram[1].write(0x801F, 0xF0);
+        memory.write(0x9800, 0x01);
+        // BGP would swap the colours around if it were applied.
+        memory.write(0xFF47, 0x1B);
+        // Bank 1, palette 5, flipped both ways.
+        memory.vram[1].write(0x9800, 0x6D);
+        memory.write(0xFF40, 0x91);
+        display.update(&mut memory, 456);
+        let line = display.lines[0];
+        assert!(line.pixels[..4] == [Color::C0; 4]);
+        assert!(line.pixels[4..8] == [Color::C3; 4]);
+        assert!(line.palettes[..8] == [5; 8]);
+        assert!(line.palettes[8] == 0);
+
+        // Without the bank bit the blank tile in bank 0 is drawn a frame
+        // later.
+        memory.vram[1].write(0x9800, 0x65);
+        display.update(&mut memory, 456 * 154);
+        assert!(display.lines[0].pixels[..8] == [Color::C0; 8]);
+
+        // Without the flips the bottom row is drawn on the last line of the
+        // tile, on the left.
+        memory.vram[1].write(0x9800, 0x08);
+        display.update(&mut memory, 456 * 154 + 456 * 7);
+        assert!(display.lines[0].pixels[..8] == [Color::C0; 8]);
+        assert!(display.lines[7].pixels[..4] == [Color::C3; 4]);
+        assert!(display.lines[7].pixels[4..8] == [Color::C0; 4]);
+    }
+
+    #[test]
+    fn test_cgb_object_priority() {
+        let mut memory = memory::MemoryMap::with_mode(Mode::GBC);
+        let mut display = Display::new();
+        // Background tile 1 in bank 1 is colour 3 on the right of its
+        // top row once flipped, object tile 2 is solid colour 1.
+        memory.vram[1].write(0x801E, 0xF0);
+        memory.vram[1].write(0x801F, 0xF0);
+        for row in 0..8 {
+            memory.write(0x8020 + row * 2, 0xFF);
+        }
+        memory.write(0x9800, 0x01);
+        memory.vram[1].write(0x9800, 0x68);
+        write_object(&mut memory, 0, [16, 8, 0x02, 0x03]);
+        memory.write(0xFF40, 0x93);
+        display.update(&mut memory, 456);
+        let line = display.lines[0];
+        assert!(line.pixels[..8] == [Color::C1; 8]);
+        assert!(line.palettes[..8] == [11; 8]);
+
+        // The map entry's priority bit puts background colours 1-3 on top.
+        memory.vram[1].write(0x9800, 0xE8);
+        display.update(&mut memory, 456 * 154);
+        let line = display.lines[0];
+        assert!(line.pixels[..4] == [Color::C1; 4]);
+        assert!(line.palettes[..4] == [11; 4]);
+        assert!(line.pixels[4..8] == [Color::C3; 4]);
+        assert!(line.palettes[4..8] == [0; 4]);
+
+        // Clearing LCDC.0 keeps the background but gives objects priority.
+        memory.write(0xFF40, 0x92);
+        display.update(&mut memory, 456 * 154);
+        assert!(display.lines[0].pixels[..8] == [Color::C1; 8]);
+
+        // Objects overlap in OAM order, wherever they sit.
+        write_object(&mut memory, 0, [16, 12, 0x02, 0x01]);
+        write_object(&mut memory, 1, [16, 8, 0x02, 0x02]);
+        display.update(&mut memory, 456 * 154);
+        let line = display.lines[0];
+        assert!(line.palettes[..4] == [10; 4]);
+        assert!(line.palettes[4..12] == [9; 8]);
+    }
+
+    #[test]
+    fn test_color_palette_registers() {
+        let mut memory = memory::MemoryMap::new();
+        memory.write(0xFF68, 0x80);
+        assert!(memory.read(0xFF68) == 0xFF);
+        assert!(memory.read(0xFF69) == 0xFF);
+
+        let mut memory = memory::MemoryMap::with_mode(Mode::GBC);
+        // Palette 1 with auto increment: red, green, blue and black.
+        memory.write(0xFF68, 0x88);
+        for byte in [0x1F, 0x00, 0xE0, 0x03, 0x00, 0x7C, 0x00, 0x00] {
+            memory.write(0xFF69, byte);
+        }
+        assert!(memory.read(0xFF68) == 0xD0);
+        memory.write(0xFF68, 0x0A);
+        assert!(memory.read(0xFF69) == 0xE0);
+        memory.write(0xFF69, 0xE1);
+        assert!(memory.read(0xFF68) == 0x4A);
+        assert!(memory.read(0xFF69) == 0xE1);
+        let palettes = memory.io_registers.bcp;
+        assert!(palettes.rgb555(1, Color::C0) == 0x001F);
+        assert!(palettes.rgb555(1, Color::C1) == 0x03E1);
+        assert!(palettes.rgb555(1, Color::C2) == 0x7C00);
+        assert!(palettes.rgb555(0, Color::C3) == 0x7FFF);
+
+        // Palette RAM can't be touched in mode 3, though the index still
+        // moves on.
+        memory.write(0xFF6A, 0xBF);
+        memory.io_registers.stat.stat |= 0b11;
+        assert!(memory.read(0xFF6B) == 0xFF);
+        memory.write(0xFF6B, 0x00);
+        assert!(memory.read(0xFF6A) == 0xC0);
+        memory.io_registers.stat.stat &= !0b11;
+        assert!(memory.read(0xFF6B) == 0xFF);
+        memory.write(0xFF6A, 0x3F);
+        assert!(memory.read(0xFF6B) == 0xFF);
+
+        assert!(graphics::rgb555_to_rgba(0x7FFF) == [0xFF; 4]);
+        assert!(graphics::rgb555_to_rgba(0x001F) == [0xFF, 0x00, 0x00, 0xFF]);
+        assert!(graphics::rgb555_to_rgba(0x4210) == [0x84, 0x84, 0x84, 0xFF]);
+    }
+
+    #[test]
+    fn test_cgb_colors() {
+        let mut memory = memory::MemoryMap::with_mode(Mode::GBC);
+        let mut display = Display::new();
+        // Background tile 1 in bank 1 is colour 3 in the left half of its
+        // bottom row, object tile 2 is solid colour 1.
+        memory.vram[1].write(0x801E, 0xF0);
+        memory.vram[1].write(0x801F, 0xF0);
+        for row in 0..8 {
+            memory.write(0x8020 + row * 2, 0xFF);
+        }
+        memory.write(0x9800, 0x01);
+        memory.vram[1].write(0x9800, 0x09);
+        memory.write(0xFF68, 0x88);
+        for byte in [0x1F, 0x00, 0xE0, 0x03, 0x00, 0x7C, 0x00, 0x00] {
+            memory.write(0xFF69, byte);
+        }
+        // Object palette 2, colour 1 is green.
+        memory.write(0xFF6A, 0x92);
+        memory.write(0xFF6B, 0xE0);
+        memory.write(0xFF6B, 0x03);
+        write_object(&mut memory, 0, [16, 8, 0x02, 0x02]);
+        memory.write(0xFF40, 0x93);
+        display.update(&mut memory, 456 * 8);
+        let colors = display.rgb555();
+        assert!(colors.len() == 160 * 144);
+        assert!(colors[..8] == [0x03E0; 8]);
+        // The next map entry still uses palette 0, which starts out white.
+        assert!(colors[8] == 0x7FFF);
+        assert!(colors[7 * 160..7 * 160 + 8] == [0x03E0; 8]);
+        let rgba = display.cgb_rgba();
+        assert!(display.rgba(ColorScheme::Greyscale) == rgba);
+        assert!(rgba[8 * 4..9 * 4] == [0xFF; 4]);
+        assert!(rgba[..4] == [0x00, 0xFF, 0x00, 0xFF]);
+
+        // Without the object the bottom row of the tile shows palette 1's
+        // colour 3 a frame later.
+        memory.write(0xFF40, 0x91);
+        display.update(&mut memory, 456 * 154);
+        assert!(display.lines[7].colors[..4] == [0x0000; 4]);
+        assert!(display.lines[7].colors[4..8] == [0x001F; 4]);
+    }
+
+    #[test]
+    fn test_wram_banks() {
+        let mut memory = memory::MemoryMap::new();
+        memory.write(0xD000, 0x12);
+        memory.write(0xFF70, 0x02);
+        assert!(memory.read(0xFF70) == 0xFF);
+        assert!(memory.read(0xD000) == 0x12);
+
+        let mut memory = memory::MemoryMap::with_mode(Mode::GBC);
+        assert!(memory.read(0xFF70) == 0xF8);
+        memory.write(0xC000, 0xC0);
+        for bank in 1..8 {
+            memory.write(0xFF70, bank);
+            memory.write(0xDFFF, bank * 0x11);
+        }
+        // Bank 0 selects bank 1.
+        memory.write(0xFF70, 0x00);
+        assert!(memory.read(0xDFFF) == 0x11);
+        memory.write(0xFF70, 0xFB);
+        assert!(memory.read(0xFF70) == 0xFB);
+        assert!(memory.read(0xDFFF) == 0x33);
+        assert!(memory.read(0xC000) == 0xC0);
+
+        let mut state = StateWriter::new();
+        memory.save_state(&mut state);
+        let state = state.finish();
+        let mut loaded = memory::MemoryMap::with_mode(Mode::GBC);
+        let mut reader = StateReader::new(&state).unwrap();
+        loaded.load_state(&mut reader).unwrap();
+        assert!(loaded.read(0xDFFF) == 0x33);
+        for bank in 1..8 {
+            loaded.write(0xFF70, bank);
+            assert!(loaded.read(0xDFFF) == bank * 0x11);
+        }
+    }
+
+    #[test]
+    fn test_general_purpose_hdma() {
+        let mut memory = memory::MemoryMap::new();
+        memory.write(0xC000, 0x12);
+        for (address, value) in (0xFF51..).zip([0xC0, 0x00, 0x80, 0x00]) {
+            memory.write(address, value);
+        }
+        memory.write(0xFF55, 0x00);
+        assert!(memory.read(0xFF55) == 0xFF);
+        assert!(memory.read(0x8000) == 0x00);
+
+        let mut gameboy = GameBoy::new(Mode::GBC, None);
+        for offset in 0..0x30 {
+            gameboy.memory.write(0xC000 + offset, offset as u8 + 1);
+        }
+        // The low four bits of both addresses are ignored, as are the top
+        // three of the destination.
+        for (address, value) in (0xFF51..).zip([0xC0, 0x05, 0xE1, 0x0F]) {
+            gameboy.memory.write(address, value);
+        }
+        /*
+        LD  A,0x01
+        LDH (0x55),A
+        PANIC
+        */
+        for (address, byte) in (ENTRY_POINT..).zip([0x3E, 0x01, 0xE0, 0x55, 0xDB]) {
+            gameboy.memory.write(address, byte);
+        }
+        gameboy.step();
+        gameboy.step();
+        for offset in 0..0x20 {
+            assert!(gameboy.memory.read(0x8100 + offset) == offset as u8 + 1);
+        }
+        assert!(gameboy.memory.read(0x8120) == 0x00);
+        assert!(gameboy.memory.read(0xFF55) == 0xFF);
+        // The CPU waits out the two blocks before its next instruction.
+        assert!(gameboy.step() == 64);
+        assert!(gameboy.cpu.registers.pc.programcounter == ENTRY_POINT + 4);
+        // HDMA1-4 are write only.
+        assert!(gameboy.memory.read(0xFF51) == 0xFF);
+    }
+
+    #[test]
+    fn test_hblank_hdma() {
+        let mut memory = memory::MemoryMap::with_mode(Mode::GBC);
+        let mut display = Display::new();
+        for offset in 0..0x40 {
+            memory.write(0xC000 + offset, offset as u8 + 1);
+        }
+        for (address, value) in (0xFF51..).zip([0xC0, 0x00, 0x80, 0x00]) {
+            memory.write(address, value);
+        }
+        memory.write(0xFF55, 0x83);
+        assert!(memory.read(0xFF55) == 0x03);
+        memory.write(0xFF40, 0x80);
+        display.update(&mut memory, 456);
+        assert!(memory.read(0x800F) == 0x10);
+        assert!(memory.read(0x8010) == 0x00);
+        assert!(memory.read(0xFF55) == 0x02);
+        assert!(memory.take_vram_dma_stall() == 32);
+
+        // Cancelling leaves the blocks still to copy in HDMA5, with bit 7
+        // set.
+        memory.write(0xFF55, 0x00);
+        assert!(memory.read(0xFF55) == 0x82);
+        display.update(&mut memory, 456);
+        assert!(memory.read(0x8010) == 0x00);
+
+        // Starting again carries on from where it stopped.
+        memory.write(0xFF55, 0x82);
+        display.update(&mut memory, 456 * 3);
+        for offset in 0..0x40 {
+            assert!(memory.read(0x8000 + offset) == offset as u8 + 1);
+        }
+        assert!(memory.read(0xFF55) == 0xFF);
+        display.update(&mut memory, 456);
+        assert!(memory.read(0x8040) == 0x00);
+    }
+
+    #[test]
+    fn test_oam_dma() {
+        let mut memory = memory::MemoryMap::new();
+        for offset in 0..0xA0 {
+            memory.write(0xC000 + offset, offset as u8);
+        }
+        memory.write(0x8000, 0x42);
+        memory.write(0xFF80, 0x24);
+        memory.write(0xFF46, 0xC0);
+        memory.tick_dma(4 * 10);
+        assert!(memory.read(0xFE00) == 0xFF);
+        assert!(memory.read(0xD000) == 0x0A);
+        assert!(memory.read(0x0000) == 0x0A);
+        assert!(memory.read(0x8000) == 0x42);
+        assert!(memory.read(0xFF80) == 0x24);
+        assert!(memory.read(0xFF46) == 0xC0);
+        memory.write(0xC010, 0x99);
+        memory.write(0x8000, 0x43);
+        memory.tick_dma(4 * 150);
+        assert!(memory.read(0xC010) == 0x10);
+        assert!(memory.read(0x8000) == 0x43);
+        for index in 0..40 {
+            let offset = index as u8 * 4;
+            assert!(memory.oam().entry(index) == [offset, offset + 1, offset + 2, offset + 3]);
+        }
+    }
+
+    #[test]
+    fn test_oam_dma_from_echo() {
+        let mut memory = memory::MemoryMap::new();
+        for offset in 0..0xA0 {
+            memory.write(0xDE00 + offset, offset as u8);
+            memory.write(0xDF00 + offset, 0xFF - offset as u8);
+        }
+        memory.write(0xFF46, 0xFE);
+        memory.tick_dma(4 * 160);
+        assert!(memory.read(0xFE00) == 0x00);
+        assert!(memory.read(0xFE9F) == 0x9F);
+        memory.write(0xFF46, 0xFF);
+        memory.tick_dma(4 * 160);
+        assert!(memory.read(0xFE00) == 0xFF);
+        assert!(memory.read(0xFE9F) == 0x60);
+    }
+
+    #[test]
+    fn test_oam_dma_from_hram() {
+        let mut gameboy = GameBoy::default();
+        for offset in 0..0xA0 {
+            gameboy.memory.write(0xC000 + offset, 0xA0 - offset as u8);
+        }
+        /*
+        CALL 0xFF80
+        LD   B,0x01
+        PANIC
+        */
+        let bytes = [0xCD, 0xFF, 0x80, 0x06, 0x01, 0xDB];
+        for (address, byte) in (ENTRY_POINT..).zip(bytes) {
+            gameboy.memory.write(address, byte);
+        }
+        /*
+        LD   A,0xC0
+        LDH  (0x46),A
+        LD   A,0x28
+        wait:
+        DEC  A
+        CP   0x00
+        JR   NZ,wait
+        RET
+        */
+        let routine = [
+            0x3E, 0xC0, 0xE0, 0x46, 0x3E, 0x28, 0x3D, 0xFE, 0x00, 0x20, 0xFB, 0xC9,
+        ];
+        for (address, byte) in (0xFF80..).zip(routine) {
+            gameboy.memory.write(address, byte);
+        }
+        while gameboy.cpu.is_running {
+            gameboy.step();
+        }
+        assert!(gameboy.cpu.registers.bc.b == 0x01);
+        assert!(gameboy.memory.read(0xFE00) == 0xA0);
+        assert!(gameboy.memory.read(0xFE9F) == 0x01);
+    }
 }
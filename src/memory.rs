@@ -1,4 +1,11 @@
-use crate::graphics::{self, Tile};
+use crate::{
+    Mode,
+    audio::Apu,
+    cartridge::Cartridge,
+    graphics::{self, Tile},
+    hardware::Joypad,
+    savestate::{SaveState, SaveStateError, StateReader, StateWriter},
+};
 
 pub trait Memory {
     fn read(&self, address: u16) -> u8;
@@ -31,38 +38,77 @@ macro_rules! memory_region {
                 self.memory[(address - $offset) as usize] = value;
             }
         }
+
+        impl SaveState for $name {
+            fn save_state(&self, writer: &mut StateWriter) {
+                writer.write_bytes(&self.memory);
+            }
+            fn load_state(&mut self, reader: &mut StateReader) -> Result<(), SaveStateError> {
+                reader.read_bytes(&mut self.memory)
+            }
+        }
     };
 }
 
 memory_region!(Rom0, 0x4000, 0x0000);
 memory_region!(RomX, 0x4000, 0x4000);
+/// The 384 tiles at 0x8000-0x97FF, in three blocks of 128.
 #[derive(Copy, Clone, PartialEq)]
 pub struct TileData {
     block0: [u8; 0x0800],
     block1: [u8; 0x0800],
     block2: [u8; 0x0800],
+    /// Every tile decoded, kept in step with the blocks on each write.
+    tiles: [Tile; 384],
 }
 impl TileData {
-    pub fn get_tile(self, lcdc: LCDC, id: u8) -> graphics::Tile {
-        let lcdc4: bool = lcdc.lcdcontrol & 0b0001_0000 == 0b0001_0000;
-        let start = id as usize;
-        let end = 16usize;
-        let bytes: [u8; 16] = match id {
-            0..=127 => match lcdc4 {
-                true => self.block0,
-                false => self.block1,
-            },
-            128..=255 => self.block1,
-        }[start..=end]
+    pub fn new() -> Self {
+        Self {
+            block0: [0; 0x0800],
+            block1: [0; 0x0800],
+            block2: [0; 0x0800],
+            tiles: [Tile::new([0; 16]); 384],
+        }
+    }
+    /// Tile `id` as the background and window address it. With LCDC.4 set
+    /// ids count up from 0x8000, otherwise they are signed from 0x9000.
+    pub fn get_tile(&self, lcdc: LCDC, id: u8) -> Tile {
+        let index = match (lcdc.unsigned_tile_data(), id) {
+            (true, _) | (false, 128..=255) => id as usize,
+            (false, 0..=127) => 256 + id as usize,
+        };
+        self.tile(index)
+    }
+    /// Tile `index` counting from 0x8000, the way objects address them.
+    pub fn tile(&self, index: usize) -> Tile {
+        self.tiles[index]
+    }
+    /// Decodes the tile containing `address` again after it was written.
+    fn invalidate(&mut self, address: u16) {
+        let index = (address - 0x8000) as usize / 16;
+        let offset = index % 128 * 16;
+        let block = match index / 128 {
+            0 => &self.block0,
+            1 => &self.block1,
+            _ => &self.block2,
+        };
+        let bytes: [u8; 16] = block[offset..offset + 16]
             .try_into()
             .expect("Error getting tiledata");
-        graphics::Tile::new(bytes)
+        self.tiles[index] = Tile::new(bytes);
+    }
+}
+impl Default for TileData {
+    fn default() -> Self {
+        Self::new()
     }
 }
 #[derive(Copy, Clone, PartialEq)]
 pub struct TileMap {
     pub map: [u8; 1024],
 }
+/// One bank of VRAM. The CGB's second bank holds another 384 tiles and,
+/// where the first holds the tile maps, the attributes of each map entry.
 #[derive(Copy, Clone, PartialEq)]
 pub struct VRam {
     pub tiledata: TileData,
@@ -79,11 +125,7 @@ impl Default for VRam {
 impl VRam {
     pub fn new() -> Self {
         Self {
-            tiledata: TileData {
-                block0: [0; 0x0800],
-                block1: [0; 0x0800],
-                block2: [0; 0x0800],
-            },
+            tiledata: TileData::new(),
             tilemap0: TileMap { map: [0; 0x0400] },
             tilemap1: TileMap { map: [0; 0x0400] },
         }
@@ -109,6 +151,28 @@ impl Memory for VRam {
             0x9C00..=0x9FFF => self.tilemap1.map[(address - 0x9C00) as usize] = value,
             _ => unreachable!(),
         }
+        if address < 0x9800 {
+            self.tiledata.invalidate(address);
+        }
+    }
+}
+impl SaveState for VRam {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_bytes(&self.tiledata.block0);
+        writer.write_bytes(&self.tiledata.block1);
+        writer.write_bytes(&self.tiledata.block2);
+        writer.write_bytes(&self.tilemap0.map);
+        writer.write_bytes(&self.tilemap1.map);
+    }
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), SaveStateError> {
+        reader.read_bytes(&mut self.tiledata.block0)?;
+        reader.read_bytes(&mut self.tiledata.block1)?;
+        reader.read_bytes(&mut self.tiledata.block2)?;
+        for address in (0x8000..0x9800).step_by(16) {
+            self.tiledata.invalidate(address);
+        }
+        reader.read_bytes(&mut self.tilemap0.map)?;
+        reader.read_bytes(&mut self.tilemap1.map)
     }
 }
 memory_region!(SRam, 0x2000, 0xA000);
@@ -124,6 +188,12 @@ impl Oam {
     pub fn new() -> Self {
         Self { memory: [0; 0x0A0] }
     }
+    /// The Y, X, tile and attribute bytes of one of the 40 objects.
+    pub fn entry(&self, index: usize) -> [u8; 4] {
+        self.memory[index * 4..index * 4 + 4]
+            .try_into()
+            .expect("Error getting OAM entry")
+    }
 }
 impl Memory for Oam {
     fn read(&self, address: u16) -> u8 {
@@ -134,28 +204,185 @@ impl Memory for Oam {
     }
 }
 
+impl SaveState for Oam {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_bytes(&self.memory);
+    }
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), SaveStateError> {
+        reader.read_bytes(&mut self.memory)
+    }
+}
+
 memory_region!(UnusedMemory, 0x0060, 0xFEA0);
 
-#[derive(Default, Copy, Clone, PartialEq)]
+/// P1. The game selects the d-pad, the buttons or both through bits 4 and
+/// 5 and reads the selected input lines in the low nibble, all active low.
+#[derive(Copy, Clone, PartialEq)]
 pub struct JoyPadIO {
+    pub select: u8,
+    /// Right, left, up and down from bit 0, low while held.
+    pub dpad: u8,
+    /// A, B, select and start from bit 0, low while held.
     pub buttons: u8,
 }
+impl JoyPadIO {
+    pub fn new() -> Self {
+        Self {
+            select: 0b0000_0000,
+            dpad: 0x0F,
+            buttons: 0x0F,
+        }
+    }
+    pub fn read(&self) -> u8 {
+        0b1100_0000 | self.select | self.lines()
+    }
+    /// The selected lines ANDed together.
+    fn lines(&self) -> u8 {
+        let mut lines = 0x0F;
+        if self.select & 0b0001_0000 == 0 {
+            lines &= self.dpad;
+        }
+        if self.select & 0b0010_0000 == 0 {
+            lines &= self.buttons;
+        }
+        lines
+    }
+    /// Makes a change and returns whether any line went from high to low,
+    /// which requests the joypad interrupt.
+    fn update(&mut self, change: impl FnOnce(&mut Self)) -> bool {
+        let lines = self.lines();
+        change(self);
+        lines & !self.lines() != 0
+    }
+    pub fn write_select(&mut self, value: u8) -> bool {
+        self.update(|joypad| joypad.select = value & 0b0011_0000)
+    }
+    pub fn set_inputs(&mut self, dpad: u8, buttons: u8) -> bool {
+        self.update(|joypad| {
+            joypad.dpad = dpad & 0x0F;
+            joypad.buttons = buttons & 0x0F;
+        })
+    }
+}
+impl Default for JoyPadIO {
+    fn default() -> Self {
+        Self::new()
+    }
+}
 #[derive(Default, Copy, Clone, PartialEq)]
 pub struct SerialIO {
     pub serial_data: u8,
     pub transfer_control: u8,
 }
+/// DIV is the upper byte of a 16 bit counter that runs every T-cycle. TIMA
+/// counts falling edges of the counter bit selected by TAC, ANDed with the
+/// TAC enable bit, so resetting DIV or changing TAC can also bump it.
 #[derive(Default, Copy, Clone, PartialEq)]
 pub struct TimerAndDivider {
-    pub divider_register: u8,
+    pub system_counter: u16,
     pub timer_counter: u8,
     pub timer_modulo: u8,
     pub timer_control: u8,
+    /// TIMA overflowed and reads 0x00 for one M-cycle before TMA is loaded.
+    pub reload_pending: bool,
+}
+impl TimerAndDivider {
+    pub fn divider(&self) -> u8 {
+        (self.system_counter >> 8) as u8
+    }
+    fn timer_input(&self) -> bool {
+        let bit = match self.timer_control & 0b11 {
+            0b00 => 9,
+            0b01 => 3,
+            0b10 => 5,
+            _ => 7,
+        };
+        self.timer_control & 0b100 != 0 && self.system_counter & (1 << bit) != 0
+    }
+    fn increment_timer(&mut self) {
+        let (timer_counter, overflow) = self.timer_counter.overflowing_add(1);
+        self.timer_counter = timer_counter;
+        self.reload_pending = overflow;
+    }
+    /// Changes the counter or TAC, incrementing TIMA on a falling edge.
+    fn update(&mut self, change: impl FnOnce(&mut Self)) {
+        let input = self.timer_input();
+        change(self);
+        if input && !self.timer_input() {
+            self.increment_timer();
+        }
+    }
+    /// The frame sequencer steps when bit 4 of DIV falls, or bit 5 in double
+    /// speed mode so it keeps to 512 Hz.
+    pub fn frame_sequencer_input(&self, double_speed: bool) -> bool {
+        let bit = match double_speed {
+            true => 13,
+            false => 12,
+        };
+        self.system_counter & (1 << bit) != 0
+    }
+    pub fn reset_divider(&mut self) {
+        self.update(|timer| timer.system_counter = 0);
+    }
+    pub fn write_timer_control(&mut self, value: u8) {
+        self.update(|timer| timer.timer_control = value & 0b111);
+    }
+    /// Writing TIMA while a reload is pending cancels it.
+    pub fn write_timer_counter(&mut self, value: u8) {
+        self.timer_counter = value;
+        self.reload_pending = false;
+    }
+    /// Runs the timer for `cycles` T-cycles. Returns whether TIMA was
+    /// reloaded from TMA, which requests the timer interrupt.
+    pub fn tick(&mut self, cycles: u32) -> bool {
+        let mut interrupt = false;
+        for _ in 0..cycles / 4 {
+            if self.reload_pending {
+                self.reload_pending = false;
+                self.timer_counter = self.timer_modulo;
+                interrupt = true;
+            }
+            self.update(|timer| timer.system_counter = timer.system_counter.wrapping_add(4));
+        }
+        interrupt
+    }
 }
 #[derive(Default, Copy, Clone, PartialEq)]
 pub struct InterruptFlags {
     pub interrupt_flag: u8,
 }
+impl InterruptFlags {
+    pub fn request(&mut self, interrupt: Interrupt) {
+        self.interrupt_flag |= interrupt.bit();
+    }
+    pub fn clear(&mut self, interrupt: Interrupt) {
+        self.interrupt_flag &= !interrupt.bit();
+    }
+}
+/// Interrupt sources in IE and IF bit order, which is also their priority.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Interrupt {
+    VBlank,
+    Stat,
+    Timer,
+    Serial,
+    Joypad,
+}
+impl Interrupt {
+    pub const PRIORITY: [Interrupt; 5] = [
+        Interrupt::VBlank,
+        Interrupt::Stat,
+        Interrupt::Timer,
+        Interrupt::Serial,
+        Interrupt::Joypad,
+    ];
+    pub fn bit(self) -> u8 {
+        1 << self as u8
+    }
+    pub fn vector(self) -> u16 {
+        0x0040 + 0x0008 * self as u16
+    }
+}
 #[derive(Default, Copy, Clone, PartialEq)]
 pub struct AudioRegisters {
     pub master_control: u8,
@@ -186,6 +413,60 @@ pub struct AudioRegisters {
 pub struct LCDC {
     lcdcontrol: u8,
 }
+impl LCDC {
+    fn bit(&self, bit: u8) -> bool {
+        self.lcdcontrol & (1 << bit) != 0
+    }
+    pub fn lcd_enabled(&self) -> bool {
+        self.bit(7)
+    }
+    /// Tiles 0-127 come from 0x8000 instead of 0x9000, with ids as u8
+    /// rather than i8.
+    pub fn unsigned_tile_data(&self) -> bool {
+        self.bit(4)
+    }
+    pub fn bg_tile_map(&self) -> u16 {
+        match self.bit(3) {
+            true => 0x9C00,
+            false => 0x9800,
+        }
+    }
+    pub fn window_tile_map(&self) -> u16 {
+        match self.bit(6) {
+            true => 0x9C00,
+            false => 0x9800,
+        }
+    }
+    pub fn window_enabled(&self) -> bool {
+        self.bit(5)
+    }
+    pub fn tall_objects(&self) -> bool {
+        self.bit(2)
+    }
+    pub fn objects_enabled(&self) -> bool {
+        self.bit(1)
+    }
+    pub fn bg_enabled(&self) -> bool {
+        self.bit(0)
+    }
+}
+/// Bits 3-6 select the STAT interrupt sources, bit 2 is set while LY equals
+/// LYC and bits 0-1 hold the PPU mode. Only the source bits are writable.
+#[derive(Default, Copy, Clone, PartialEq)]
+#[allow(clippy::upper_case_acronyms)]
+pub struct STAT {
+    pub stat: u8,
+}
+#[derive(Default, Copy, Clone, PartialEq)]
+#[allow(clippy::upper_case_acronyms)]
+pub struct LY {
+    pub line: u8,
+}
+#[derive(Default, Copy, Clone, PartialEq)]
+#[allow(clippy::upper_case_acronyms)]
+pub struct LYC {
+    pub compare: u8,
+}
 #[derive(Default, Copy, Clone, PartialEq)]
 #[allow(clippy::upper_case_acronyms)]
 pub struct SCY {
@@ -197,40 +478,290 @@ pub struct SCX {
     pub scroll_x: u8,
 }
 
+/// Maps the four colour ids to shades, two bits each starting with id 0.
+#[derive(Default, Copy, Clone, PartialEq)]
+pub struct Palette {
+    pub palette: u8,
+}
+impl Palette {
+    pub fn shade(&self, color: graphics::Color) -> graphics::Color {
+        graphics::Color::from_bits((self.palette >> (color.as_bits() * 2)) & 0b11)
+            .expect("Two bits are always a colour")
+    }
+}
+#[derive(Default, Copy, Clone, PartialEq)]
+#[allow(clippy::upper_case_acronyms)]
+pub struct WY {
+    pub window_y: u8,
+}
+/// The window's left edge plus 7.
+#[derive(Default, Copy, Clone, PartialEq)]
+#[allow(clippy::upper_case_acronyms)]
+pub struct WX {
+    pub window_x: u8,
+}
 #[derive(Default, Copy, Clone, PartialEq)]
 #[allow(clippy::upper_case_acronyms)]
 pub struct DMA {
     pub oam_dma: u8,
+    /// Bytes copied so far while a transfer is running.
+    pub transferred: Option<u8>,
+}
+impl DMA {
+    /// Pages 0xE0-0xFF read from work RAM, like the echo region.
+    pub fn source(&self) -> u16 {
+        match self.oam_dma {
+            0xE0..=0xFF => (self.oam_dma as u16 - 0x20) << 8,
+            page => (page as u16) << 8,
+        }
+    }
+}
+const OAM_DMA_LENGTH_MINUS_ONE: u8 = 0x9F;
+/// T-cycles the CPU is held for while VRAM DMA copies a 16 byte block, twice
+/// as many in double speed mode.
+const VRAM_DMA_BLOCK_CYCLES: u32 = 32;
+
+/// CGB VRAM DMA. HDMA1-4 set where it copies from and to, both of which move
+/// on as blocks are copied so a cancelled HBlank transfer can be resumed.
+#[derive(Copy, Clone, PartialEq)]
+#[allow(clippy::upper_case_acronyms)]
+pub struct HDMA {
+    pub source: u16,
+    /// Offset into VRAM.
+    pub destination: u16,
+    /// Blocks left to copy minus one, 0x7F once a transfer has finished.
+    pub blocks: u8,
+    /// An HBlank transfer is running, copying a block each HBlank.
+    pub hblank: bool,
+    /// T-cycles the CPU still has to wait for blocks already copied.
+    pub stall: u32,
+}
+impl HDMA {
+    pub fn new() -> Self {
+        Self {
+            source: 0,
+            destination: 0,
+            blocks: 0x7F,
+            hblank: false,
+            stall: 0,
+        }
+    }
+    /// Bit 7 is clear while an HBlank transfer is running and the rest is
+    /// the number of blocks left minus one.
+    pub fn status(&self) -> u8 {
+        ((!self.hblank as u8) << 7) | self.blocks
+    }
+    pub fn write_source_high(&mut self, value: u8) {
+        self.source = (self.source & 0x00F0) | ((value as u16) << 8);
+    }
+    pub fn write_source_low(&mut self, value: u8) {
+        self.source = (self.source & 0xFF00) | (value & 0xF0) as u16;
+    }
+    pub fn write_destination_high(&mut self, value: u8) {
+        self.destination = (self.destination & 0x00F0) | (((value & 0x1F) as u16) << 8);
+    }
+    pub fn write_destination_low(&mut self, value: u8) {
+        self.destination = (self.destination & 0x1F00) | (value & 0xF0) as u16;
+    }
+}
+impl Default for HDMA {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The separate buses an OAM DMA can tie up.
+#[derive(Copy, Clone, PartialEq)]
+enum Bus {
+    External,
+    Video,
+    Oam,
+    Internal,
+}
+impl Bus {
+    fn of(address: u16) -> Self {
+        match address {
+            0x8000..=0x9FFF => Bus::Video,
+            0xFE00..=0xFEFF => Bus::Oam,
+            0xFF00..=0xFFFF => Bus::Internal,
+            _ => Bus::External,
+        }
+    }
+}
+/// CGB speed switch. Bit 7 is the current speed and is read only, bit 0
+/// arms a switch that happens on the next STOP.
+#[derive(Default, Copy, Clone, PartialEq)]
+#[allow(clippy::upper_case_acronyms)]
+pub struct KEY1 {
+    pub speed_switch: u8,
+}
+impl KEY1 {
+    pub fn double_speed(&self) -> bool {
+        self.speed_switch & 0b1000_0000 != 0
+    }
+    pub fn switch_armed(&self) -> bool {
+        self.speed_switch & 0b0000_0001 != 0
+    }
+    pub fn switch_speed(&mut self) {
+        self.speed_switch = (self.speed_switch ^ 0b1000_0000) & 0b1000_0000;
+    }
+}
+/// CGB VRAM bank select. Only bit 0 is used, the rest read as 1.
+#[derive(Default, Copy, Clone, PartialEq)]
+#[allow(clippy::upper_case_acronyms)]
+pub struct VBK {
+    pub vram_bank: u8,
 }
+impl VBK {
+    pub fn bank(&self) -> usize {
+        (self.vram_bank & 0b1) as usize
+    }
+}
+/// CGB WRAM bank select for 0xD000-0xDFFF. Only bits 0-2 are used and
+/// bank 0 selects bank 1.
+#[derive(Default, Copy, Clone, PartialEq)]
+#[allow(clippy::upper_case_acronyms)]
+pub struct SVBK {
+    pub wram_bank: u8,
+}
+impl SVBK {
+    pub fn bank(&self) -> usize {
+        match self.wram_bank & 0b111 {
+            0 => 1,
+            bank => bank as usize,
+        }
+    }
+}
+/// CGB palette RAM for either the background or objects: eight palettes of
+/// four little endian RGB555 colours. It is reached a byte at a time through
+/// BCPS/BCPD or OCPS/OCPD.
 #[derive(Copy, Clone, PartialEq)]
+pub struct ColorPalettes {
+    /// Bits 0-5 index the byte BCPD/OCPD accesses, bit 7 moves the index on
+    /// after each write.
+    pub specification: u8,
+    pub data: [u8; 64],
+}
+impl ColorPalettes {
+    const AUTO_INCREMENT: u8 = 0b1000_0000;
+    const INDEX: u8 = 0b0011_1111;
+    pub fn new() -> Self {
+        // Everything starts out white.
+        Self {
+            specification: 0,
+            data: [0xFF; 64],
+        }
+    }
+    fn index(&self) -> usize {
+        (self.specification & Self::INDEX) as usize
+    }
+    pub fn read_specification(&self) -> u8 {
+        self.specification | 0b0100_0000
+    }
+    pub fn write_specification(&mut self, value: u8) {
+        self.specification = value & (Self::AUTO_INCREMENT | Self::INDEX);
+    }
+    pub fn read_data(&self) -> u8 {
+        self.data[self.index()]
+    }
+    /// A write while the PPU is drawing is dropped, but still moves the
+    /// index on.
+    pub fn write_data(&mut self, value: u8, locked: bool) {
+        if !locked {
+            self.data[self.index()] = value;
+        }
+        if self.specification & Self::AUTO_INCREMENT != 0 {
+            self.specification = Self::AUTO_INCREMENT | ((self.specification + 1) & Self::INDEX);
+        }
+    }
+    /// RGB555 colour of `color` in `palette`, red in the low bits.
+    pub fn rgb555(&self, palette: u8, color: graphics::Color) -> u16 {
+        let index = palette as usize * 8 + color.as_bits() as usize * 2;
+        u16::from_le_bytes([self.data[index], self.data[index + 1]]) & 0x7FFF
+    }
+}
+impl Default for ColorPalettes {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+impl SaveState for ColorPalettes {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_u8(self.specification);
+        writer.write_bytes(&self.data);
+    }
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), SaveStateError> {
+        self.specification = reader.read_u8()? & (Self::AUTO_INCREMENT | Self::INDEX);
+        reader.read_bytes(&mut self.data)
+    }
+}
+#[derive(Clone, PartialEq)]
 pub struct IORegisters {
     memory: [u8; 0x0080],
     pub joypad: JoyPadIO,
     pub serial: SerialIO,
     pub timer_and_divider: TimerAndDivider,
     pub interrupt_flags: InterruptFlags,
-    pub audio_registers: AudioRegisters,
+    pub apu: Apu,
     pub lcdcontrol: LCDC,
+    pub stat: STAT,
     pub scy: SCY,
     pub scx: SCX,
+    pub ly: LY,
+    pub lyc: LYC,
+    pub bgp: Palette,
+    pub obp0: Palette,
+    pub obp1: Palette,
+    pub wy: WY,
+    pub wx: WX,
     pub dma: DMA,
+    pub key1: KEY1,
+    pub vbk: VBK,
+    pub bcp: ColorPalettes,
+    pub ocp: ColorPalettes,
+    pub svbk: SVBK,
+    pub hdma: HDMA,
+    /// The CGB registers read as 0xFF and ignore writes on DMG.
+    pub mode: Mode,
 }
 
 impl IORegisters {
     pub fn new() -> Self {
+        Self::with_mode(Mode::DMG)
+    }
+    pub fn with_mode(mode: Mode) -> Self {
         Self {
             memory: [0; 0x0080],
-            joypad: JoyPadIO::default(),
+            joypad: JoyPadIO::new(),
             serial: SerialIO::default(),
             timer_and_divider: TimerAndDivider::default(),
             interrupt_flags: InterruptFlags::default(),
-            audio_registers: AudioRegisters::default(),
+            apu: Apu::new(),
             lcdcontrol: LCDC { lcdcontrol: 0 },
+            stat: STAT::default(),
             scy: SCY { scroll_y: 0 },
             scx: SCX { scroll_x: 0 },
+            ly: LY::default(),
+            lyc: LYC::default(),
+            bgp: Palette { palette: 0xFC },
+            obp0: Palette::default(),
+            obp1: Palette::default(),
+            wy: WY::default(),
+            wx: WX::default(),
             dma: DMA::default(),
+            key1: KEY1::default(),
+            vbk: VBK::default(),
+            bcp: ColorPalettes::new(),
+            ocp: ColorPalettes::new(),
+            svbk: SVBK::default(),
+            hdma: HDMA::new(),
+            mode,
         }
     }
+    /// Whether the PPU is in mode 3, when it has palette RAM to itself.
+    fn drawing(&self) -> bool {
+        graphics::PpuMode::from_stat(self.stat.stat) == graphics::PpuMode::Transfer
+    }
 }
 
 impl Default for IORegisters {
@@ -242,38 +773,48 @@ impl Memory for IORegisters {
     fn read(&self, address: u16) -> u8 {
         #[allow(clippy::match_overlapping_arm)]
         match address {
-            0xFF00 => self.joypad.buttons,
+            0xFF00 => self.joypad.read(),
             0xFF01 => self.serial.serial_data,
             0xFF02 => self.serial.transfer_control,
-            0xFF04 => self.timer_and_divider.divider_register,
+            0xFF04 => self.timer_and_divider.divider(),
             0xFF05 => self.timer_and_divider.timer_counter,
             0xFF06 => self.timer_and_divider.timer_modulo,
-            0xFF07 => self.timer_and_divider.timer_control,
-            0xFF0F => self.interrupt_flags.interrupt_flag,
-            0xFF10 => self.audio_registers.channel_1_sweep,
-            0xFF11 => self.audio_registers.channel_1_length_and_duty_cycle,
-            0xFF12 => self.audio_registers.channel_1_volume_and_envelope,
-            0xFF13 => self.audio_registers.channel_1_period_low,
-            0xFF14 => self.audio_registers.channel_1_period_high_and_control,
-            0xFF16 => self.audio_registers.channel_2_length_and_duty_cycle,
-            0xFF17 => self.audio_registers.channel_2_volume_and_envelope,
-            0xFF18 => self.audio_registers.channel_2_period_low,
-            0xFF19 => self.audio_registers.channel_2_period_high_and_control,
-            0xFF1A => self.audio_registers.channel_3_dac_enable,
-            0xFF1B => self.audio_registers.channel_3_length_timer,
-            0xFF1C => self.audio_registers.channel_3_output_level,
-            0xFF1D => self.audio_registers.channel_3_period_low,
-            0xFF1E => self.audio_registers.channel_3_period_high_and_control,
-            0xFF20 => self.audio_registers.channel_4_length_timer,
-            0xFF21 => self.audio_registers.channel_4_volume_and_envelope,
-            0xFF22 => self.audio_registers.channel_4_frequency_and_randomness,
-            0xFF23 => self.audio_registers.channel_4_control,
-            0xFF24 => self.audio_registers.master_volume_and_vin,
-            0xFF25 => self.audio_registers.sound_panning,
-            0xFF26 => self.audio_registers.master_control,
-            0xFF30..=0xFF3F => self.audio_registers.wave_pattern_ram[(address - 0xFF30) as usize],
+            0xFF07 => self.timer_and_divider.timer_control | 0b1111_1000,
+            0xFF0F => self.interrupt_flags.interrupt_flag | 0b1110_0000,
+            0xFF10..=0xFF3F => self.apu.read(address),
             0xFF40 => self.lcdcontrol.lcdcontrol,
+            0xFF41 => self.stat.stat | 0b1000_0000,
+            0xFF42 => self.scy.scroll_y,
+            0xFF43 => self.scx.scroll_x,
+            0xFF44 => self.ly.line,
+            0xFF45 => self.lyc.compare,
             0xFF46 => self.dma.oam_dma,
+            0xFF47 => self.bgp.palette,
+            0xFF48 => self.obp0.palette,
+            0xFF49 => self.obp1.palette,
+            0xFF4A => self.wy.window_y,
+            0xFF4B => self.wx.window_x,
+            0xFF4D => match self.mode {
+                Mode::GBC => self.key1.speed_switch | 0b0111_1110,
+                Mode::DMG => 0xFF,
+            },
+            0xFF4F => match self.mode {
+                Mode::GBC => self.vbk.vram_bank | 0b1111_1110,
+                Mode::DMG => 0xFF,
+            },
+            0xFF55 if self.mode == Mode::GBC => self.hdma.status(),
+            0xFF51..=0xFF55 => 0xFF,
+            0xFF68..=0xFF6B if self.mode == Mode::DMG => 0xFF,
+            0xFF68 => self.bcp.read_specification(),
+            0xFF69 if self.drawing() => 0xFF,
+            0xFF69 => self.bcp.read_data(),
+            0xFF6A => self.ocp.read_specification(),
+            0xFF6B if self.drawing() => 0xFF,
+            0xFF6B => self.ocp.read_data(),
+            0xFF70 => match self.mode {
+                Mode::GBC => self.svbk.wram_bank | 0b1111_1000,
+                Mode::DMG => 0xFF,
+            },
             0xFF0..=0xFF80 => self.memory[(address - 0xFF00) as usize],
             _ => unreachable!(),
         }
@@ -281,39 +822,79 @@ impl Memory for IORegisters {
     fn write(&mut self, address: u16, value: u8) {
         #[allow(clippy::match_overlapping_arm)]
         let dest = match address {
-            0xFF00 => &mut self.joypad.buttons,
+            0xFF00 => {
+                if self.joypad.write_select(value) {
+                    self.interrupt_flags.request(Interrupt::Joypad);
+                }
+                return;
+            }
             0xFF01 => &mut self.serial.serial_data,
             0xFF02 => &mut self.serial.transfer_control,
-            0xFF04 => &mut self.timer_and_divider.divider_register,
-            0xFF05 => &mut self.timer_and_divider.timer_counter,
+            0xFF04 => {
+                // Resetting DIV can make the bit the frame sequencer watches fall.
+                if self
+                    .timer_and_divider
+                    .frame_sequencer_input(self.key1.double_speed())
+                {
+                    self.apu.clock_frame_sequencer();
+                }
+                return self.timer_and_divider.reset_divider();
+            }
+            0xFF05 => return self.timer_and_divider.write_timer_counter(value),
             0xFF06 => &mut self.timer_and_divider.timer_modulo,
-            0xFF07 => &mut self.timer_and_divider.timer_control,
+            0xFF07 => return self.timer_and_divider.write_timer_control(value),
             0xFF0F => &mut self.interrupt_flags.interrupt_flag,
-            0xFF10 => &mut self.audio_registers.channel_1_sweep,
-            0xFF11 => &mut self.audio_registers.channel_1_length_and_duty_cycle,
-            0xFF12 => &mut self.audio_registers.channel_1_volume_and_envelope,
-            0xFF13 => &mut self.audio_registers.channel_1_period_low,
-            0xFF14 => &mut self.audio_registers.channel_1_period_high_and_control,
-            0xFF16 => &mut self.audio_registers.channel_2_length_and_duty_cycle,
-            0xFF17 => &mut self.audio_registers.channel_2_volume_and_envelope,
-            0xFF18 => &mut self.audio_registers.channel_2_period_low,
-            0xFF19 => &mut self.audio_registers.channel_2_period_high_and_control,
-            0xFF1A => &mut self.audio_registers.channel_3_dac_enable,
-            0xFF1C => &mut self.audio_registers.channel_3_output_level,
-            0xFF1D => &mut self.audio_registers.channel_3_period_low,
-            0xFF1E => &mut self.audio_registers.channel_3_period_high_and_control,
-            0xFF20 => &mut self.audio_registers.channel_4_length_timer,
-            0xFF21 => &mut self.audio_registers.channel_4_volume_and_envelope,
-            0xFF22 => &mut self.audio_registers.channel_4_frequency_and_randomness,
-            0xFF23 => &mut self.audio_registers.channel_4_control,
-            0xFF24 => &mut self.audio_registers.master_volume_and_vin,
-            0xFF25 => &mut self.audio_registers.sound_panning,
-            0xFF26 => &mut self.audio_registers.master_control,
-            0xFF30..=0xFF3F => {
-                &mut self.audio_registers.wave_pattern_ram[(address - 0xFF30) as usize]
-            }
+            0xFF10..=0xFF3F => return self.apu.write(address, value),
             0xFF40 => &mut self.lcdcontrol.lcdcontrol,
-            0xFF46 => &mut self.dma.oam_dma,
+            0xFF41 => {
+                self.stat.stat = (self.stat.stat & 0b0000_0111) | (value & 0b0111_1000);
+                return;
+            }
+            0xFF42 => &mut self.scy.scroll_y,
+            0xFF43 => &mut self.scx.scroll_x,
+            0xFF44 => return,
+            0xFF45 => &mut self.lyc.compare,
+            0xFF46 => {
+                self.dma.oam_dma = value;
+                self.dma.transferred = Some(0);
+                return;
+            }
+            0xFF47 => &mut self.bgp.palette,
+            0xFF48 => &mut self.obp0.palette,
+            0xFF49 => &mut self.obp1.palette,
+            0xFF4A => &mut self.wy.window_y,
+            0xFF4B => &mut self.wx.window_x,
+            0xFF4D => {
+                if self.mode == Mode::GBC {
+                    self.key1.speed_switch = (self.key1.speed_switch & 0b1000_0000) | (value & 0b1);
+                }
+                return;
+            }
+            0xFF4F => {
+                if self.mode == Mode::GBC {
+                    self.vbk.vram_bank = value & 0b1;
+                }
+                return;
+            }
+            0xFF51..=0xFF55 if self.mode == Mode::DMG => return,
+            0xFF51 => return self.hdma.write_source_high(value),
+            0xFF52 => return self.hdma.write_source_low(value),
+            0xFF53 => return self.hdma.write_destination_high(value),
+            0xFF54 => return self.hdma.write_destination_low(value),
+            // Starting a transfer needs the rest of the memory map, which
+            // handles HDMA5 itself.
+            0xFF55 => return,
+            0xFF68..=0xFF6B if self.mode == Mode::DMG => return,
+            0xFF68 => return self.bcp.write_specification(value),
+            0xFF69 => return self.bcp.write_data(value, self.drawing()),
+            0xFF6A => return self.ocp.write_specification(value),
+            0xFF6B => return self.ocp.write_data(value, self.drawing()),
+            0xFF70 => {
+                if self.mode == Mode::GBC {
+                    self.svbk.wram_bank = value & 0b111;
+                }
+                return;
+            }
             0xFF00..=0xFF80 => &mut self.memory[(address - 0xFF00) as usize],
             _ => {
                 unreachable!()
@@ -322,17 +903,165 @@ impl Memory for IORegisters {
         *dest = value;
     }
 }
+impl SaveState for IORegisters {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_bytes(&self.memory);
+        // The input lines follow the buttons held on the host, so only the
+        // selection is saved.
+        writer.write_u8(self.joypad.select);
+        writer.write_u8(self.serial.serial_data);
+        writer.write_u8(self.serial.transfer_control);
+        writer.write_u16(self.timer_and_divider.system_counter);
+        writer.write_u8(self.timer_and_divider.timer_counter);
+        writer.write_u8(self.timer_and_divider.timer_modulo);
+        writer.write_u8(self.timer_and_divider.timer_control);
+        writer.write_bool(self.timer_and_divider.reload_pending);
+        writer.write_u8(self.interrupt_flags.interrupt_flag);
+        self.apu.save_state(writer);
+        writer.write_u8(self.lcdcontrol.lcdcontrol);
+        writer.write_u8(self.stat.stat);
+        writer.write_u8(self.scy.scroll_y);
+        writer.write_u8(self.scx.scroll_x);
+        writer.write_u8(self.ly.line);
+        writer.write_u8(self.lyc.compare);
+        writer.write_u8(self.bgp.palette);
+        writer.write_u8(self.obp0.palette);
+        writer.write_u8(self.obp1.palette);
+        writer.write_u8(self.wy.window_y);
+        writer.write_u8(self.wx.window_x);
+        writer.write_u8(self.dma.oam_dma);
+        writer.write_bool(self.dma.transferred.is_some());
+        writer.write_u8(self.dma.transferred.unwrap_or(0));
+        writer.write_u8(self.key1.speed_switch);
+        writer.write_u8(self.vbk.vram_bank);
+        self.bcp.save_state(writer);
+        self.ocp.save_state(writer);
+        writer.write_u8(self.svbk.wram_bank);
+        writer.write_u16(self.hdma.source);
+        writer.write_u16(self.hdma.destination);
+        writer.write_u8(self.hdma.blocks);
+        writer.write_bool(self.hdma.hblank);
+        writer.write_u32(self.hdma.stall);
+    }
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), SaveStateError> {
+        reader.read_bytes(&mut self.memory)?;
+        self.joypad.select = reader.read_u8()? & 0b0011_0000;
+        self.serial.serial_data = reader.read_u8()?;
+        self.serial.transfer_control = reader.read_u8()?;
+        self.timer_and_divider.system_counter = reader.read_u16()?;
+        self.timer_and_divider.timer_counter = reader.read_u8()?;
+        self.timer_and_divider.timer_modulo = reader.read_u8()?;
+        self.timer_and_divider.timer_control = reader.read_u8()? & 0b111;
+        self.timer_and_divider.reload_pending = reader.read_bool()?;
+        self.interrupt_flags.interrupt_flag = reader.read_u8()?;
+        self.apu.load_state(reader)?;
+        self.lcdcontrol.lcdcontrol = reader.read_u8()?;
+        self.stat.stat = reader.read_u8()? & 0b0111_1111;
+        self.scy.scroll_y = reader.read_u8()?;
+        self.scx.scroll_x = reader.read_u8()?;
+        self.ly.line = reader.read_u8()?;
+        if self.ly.line > 153 {
+            return Err(SaveStateError::Corrupt("LY out of range"));
+        }
+        self.lyc.compare = reader.read_u8()?;
+        self.bgp.palette = reader.read_u8()?;
+        self.obp0.palette = reader.read_u8()?;
+        self.obp1.palette = reader.read_u8()?;
+        self.wy.window_y = reader.read_u8()?;
+        self.wx.window_x = reader.read_u8()?;
+        self.dma.oam_dma = reader.read_u8()?;
+        let transferring = reader.read_bool()?;
+        let transferred = reader.read_u8()?;
+        if transferred > OAM_DMA_LENGTH_MINUS_ONE {
+            return Err(SaveStateError::Corrupt("OAM DMA progress out of range"));
+        }
+        self.dma.transferred = transferring.then_some(transferred);
+        self.key1.speed_switch = reader.read_u8()? & 0b1000_0001;
+        self.vbk.vram_bank = reader.read_u8()? & 0b1;
+        self.bcp.load_state(reader)?;
+        self.ocp.load_state(reader)?;
+        self.svbk.wram_bank = reader.read_u8()? & 0b111;
+        self.hdma.source = reader.read_u16()? & 0xFFF0;
+        self.hdma.destination = reader.read_u16()? & 0x1FF0;
+        self.hdma.blocks = reader.read_u8()? & 0x7F;
+        self.hdma.hblank = reader.read_bool()?;
+        self.hdma.stall = reader.read_u32()?;
+        Ok(())
+    }
+}
+impl SaveState for AudioRegisters {
+    fn save_state(&self, writer: &mut StateWriter) {
+        for register in [
+            self.master_control,
+            self.sound_panning,
+            self.master_volume_and_vin,
+            self.channel_1_sweep,
+            self.channel_1_length_and_duty_cycle,
+            self.channel_1_volume_and_envelope,
+            self.channel_1_period_low,
+            self.channel_1_period_high_and_control,
+            self.channel_2_length_and_duty_cycle,
+            self.channel_2_volume_and_envelope,
+            self.channel_2_period_low,
+            self.channel_2_period_high_and_control,
+            self.channel_3_dac_enable,
+            self.channel_3_length_timer,
+            self.channel_3_output_level,
+            self.channel_3_period_low,
+            self.channel_3_period_high_and_control,
+            self.channel_4_length_timer,
+            self.channel_4_volume_and_envelope,
+            self.channel_4_frequency_and_randomness,
+            self.channel_4_control,
+        ] {
+            writer.write_u8(register);
+        }
+        writer.write_bytes(&self.wave_pattern_ram);
+    }
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), SaveStateError> {
+        for register in [
+            &mut self.master_control,
+            &mut self.sound_panning,
+            &mut self.master_volume_and_vin,
+            &mut self.channel_1_sweep,
+            &mut self.channel_1_length_and_duty_cycle,
+            &mut self.channel_1_volume_and_envelope,
+            &mut self.channel_1_period_low,
+            &mut self.channel_1_period_high_and_control,
+            &mut self.channel_2_length_and_duty_cycle,
+            &mut self.channel_2_volume_and_envelope,
+            &mut self.channel_2_period_low,
+            &mut self.channel_2_period_high_and_control,
+            &mut self.channel_3_dac_enable,
+            &mut self.channel_3_length_timer,
+            &mut self.channel_3_output_level,
+            &mut self.channel_3_period_low,
+            &mut self.channel_3_period_high_and_control,
+            &mut self.channel_4_length_timer,
+            &mut self.channel_4_volume_and_envelope,
+            &mut self.channel_4_frequency_and_randomness,
+            &mut self.channel_4_control,
+        ] {
+            *register = reader.read_u8()?;
+        }
+        reader.read_bytes(&mut self.wave_pattern_ram)
+    }
+}
+
 memory_region!(HRam, 0x007F, 0xFF80);
 memory_region!(IERegister, 0x0001, 0xFFFF);
 
-#[derive(Copy, Clone, PartialEq)]
+#[derive(Clone, PartialEq)]
 pub struct MemoryMap {
+    cartridge: Option<Cartridge>,
     rom0: Rom0,
     romx: RomX,
-    pub vram: VRam,
+    /// Both VRAM banks, only the first is used on DMG.
+    pub vram: [VRam; 2],
     sram: SRam,
     wram0: WRam0,
-    wramx: WRamX,
+    /// WRAM banks 1-7. DMG only has the first.
+    wramx: [WRamX; 7],
     echo: Echo,
     aom: Oam,
     unused: UnusedMemory,
@@ -341,65 +1070,232 @@ pub struct MemoryMap {
     ie_register: IERegister,
 }
 
+/// The CPU's view of memory. While OAM DMA runs OAM is unreadable and the
+/// bus the transfer reads from returns the byte being copied instead, only
+/// the other bus, IO and HRAM work normally.
 impl Memory for MemoryMap {
     fn read(&self, address: u16) -> u8 {
-        match address {
-            0x0000..=0x3FFF => self.rom0.read(address),
-            0x4000..=0x7FFF => self.romx.read(address),
-            0x8000..=0x9FFF => self.vram.read(address),
-            0xA000..=0xBFFF => self.sram.read(address),
-            0xC000..=0xCFFF => self.wram0.read(address),
-            0xD000..=0xDFFF => self.wramx.read(address),
-            0xE000..=0xFDFF => self.echo.read(address),
-            0xFE00..=0xFE9F => self.aom.read(address),
-            0xFEA0..=0xFEFF => self.unused.read(address),
-            0xFF00..=0xFF7F => self.io_registers.read(address),
-            0xFF80..=0xFFFE => self.hram.read(address),
-            0xFFFF => self.ie_register.read(address),
+        let Some(transferred) = self.io_registers.dma.transferred else {
+            return self.bus_read(address);
+        };
+        let source = self.io_registers.dma.source() + transferred as u16;
+        match Bus::of(address) {
+            Bus::Oam => 0xFF,
+            bus if bus == Bus::of(source) => self.bus_read(source),
+            _ => self.bus_read(address),
         }
     }
     fn write(&mut self, address: u16, value: u8) {
-        match address {
-            0x0000..=0x3FFF => self.rom0.write(address, value),
-            0x4000..=0x7FFF => self.romx.write(address, value),
-            0x8000..=0x9FFF => self.vram.write(address, value),
-            0xA000..=0xBFFF => self.sram.write(address, value),
-            0xC000..=0xCFFF => self.wram0.write(address, value),
-            0xD000..=0xDFFF => self.wramx.write(address, value),
-            0xE000..=0xFDFF => self.echo.write(address, value),
-            0xFE00..=0xFE9F => self.aom.write(address, value),
-            0xFEA0..=0xFEFF => self.unused.write(address, value),
-            0xFF00..=0xFF7F => self.io_registers.write(address, value),
-            0xFF80..=0xFFFE => self.hram.write(address, value),
-            0xFFFF => self.ie_register.write(address, value),
+        if self.io_registers.dma.transferred.is_some() {
+            match Bus::of(address) {
+                Bus::Oam => return,
+                bus if bus == Bus::of(self.io_registers.dma.source()) => return,
+                _ => (),
+            }
         }
+        self.bus_write(address, value);
     }
 }
 
 #[allow(dead_code)]
 impl MemoryMap {
     pub fn new() -> Self {
+        Self::with_mode(Mode::DMG)
+    }
+    pub fn with_mode(mode: Mode) -> Self {
         Self {
+            cartridge: None,
             rom0: Rom0::new(),
             romx: RomX::new(),
-            vram: VRam::new(),
+            vram: [VRam::new(); 2],
             sram: SRam::new(),
             wram0: WRam0::new(),
-            wramx: WRamX::new(),
+            wramx: [WRamX::new(); 7],
             echo: Echo::new(),
             aom: Oam::new(),
             unused: UnusedMemory::new(),
-            io_registers: IORegisters::new(),
+            io_registers: IORegisters::with_mode(mode),
             hram: HRam::new(),
             ie_register: IERegister::new(),
         }
     }
-    pub fn load_tiles(self) -> Vec<Tile> {
+    /// Inserts a cartridge. From then on 0x0000-0x7FFF and 0xA000-0xBFFF are
+    /// served by its mapper instead of the flat `Rom0`, `RomX` and `SRam`.
+    pub fn load_cartridge(&mut self, cartridge: Cartridge) {
+        self.cartridge = Some(cartridge);
+    }
+    pub fn cartridge(&self) -> Option<&Cartridge> {
+        self.cartridge.as_ref()
+    }
+    pub fn oam(&self) -> &Oam {
+        &self.aom
+    }
+    pub fn mode(&self) -> Mode {
+        self.io_registers.mode
+    }
+    /// Whether the CGB has switched the CPU and timer to double speed.
+    pub fn double_speed(&self) -> bool {
+        self.io_registers.key1.double_speed()
+    }
+    /// Reads without the restrictions of a running OAM DMA.
+    fn bus_read(&self, address: u16) -> u8 {
+        match address {
+            0x0000..=0x7FFF => match &self.cartridge {
+                Some(cartridge) => cartridge.read_rom(address),
+                None if address <= 0x3FFF => self.rom0.read(address),
+                None => self.romx.read(address),
+            },
+            0x8000..=0x9FFF => self.vram[self.io_registers.vbk.bank()].read(address),
+            0xA000..=0xBFFF => match &self.cartridge {
+                Some(cartridge) => cartridge.read_ram(address),
+                None => self.sram.read(address),
+            },
+            0xC000..=0xCFFF => self.wram0.read(address),
+            0xD000..=0xDFFF => self.wramx[self.io_registers.svbk.bank() - 1].read(address),
+            0xE000..=0xFDFF => self.echo.read(address),
+            0xFE00..=0xFE9F => self.aom.read(address),
+            0xFEA0..=0xFEFF => self.unused.read(address),
+            0xFF00..=0xFF7F => self.io_registers.read(address),
+            0xFF80..=0xFFFE => self.hram.read(address),
+            0xFFFF => self.ie_register.read(address),
+        }
+    }
+    fn bus_write(&mut self, address: u16, value: u8) {
+        match address {
+            0x0000..=0x7FFF => match &mut self.cartridge {
+                Some(cartridge) => cartridge.write_rom(address, value),
+                None if address <= 0x3FFF => self.rom0.write(address, value),
+                None => self.romx.write(address, value),
+            },
+            0x8000..=0x9FFF => self.vram[self.io_registers.vbk.bank()].write(address, value),
+            0xA000..=0xBFFF => match &mut self.cartridge {
+                Some(cartridge) => cartridge.write_ram(address, value),
+                None => self.sram.write(address, value),
+            },
+            0xC000..=0xCFFF => self.wram0.write(address, value),
+            0xD000..=0xDFFF => self.wramx[self.io_registers.svbk.bank() - 1].write(address, value),
+            0xE000..=0xFDFF => self.echo.write(address, value),
+            0xFE00..=0xFE9F => self.aom.write(address, value),
+            0xFEA0..=0xFEFF => self.unused.write(address, value),
+            0xFF55 => self.write_hdma5(value),
+            0xFF00..=0xFF7F => self.io_registers.write(address, value),
+            0xFF80..=0xFFFE => self.hram.write(address, value),
+            0xFFFF => self.ie_register.write(address, value),
+        }
+    }
+    /// Copies one byte of a running OAM DMA per M-cycle.
+    pub fn tick_dma(&mut self, cycles: u32) {
+        for _ in 0..cycles / 4 {
+            let Some(transferred) = self.io_registers.dma.transferred else {
+                return;
+            };
+            let value = self.bus_read(self.io_registers.dma.source() + transferred as u16);
+            self.aom.write(0xFE00 + transferred as u16, value);
+            self.io_registers.dma.transferred = match transferred {
+                OAM_DMA_LENGTH_MINUS_ONE => None,
+                _ => Some(transferred + 1),
+            };
+        }
+    }
+    /// Starts or cancels a VRAM DMA. A general purpose transfer is copied
+    /// straight away, with the CPU held until it would have finished.
+    fn write_hdma5(&mut self, value: u8) {
+        if self.mode() == Mode::DMG {
+            return;
+        }
+        let hdma = &mut self.io_registers.hdma;
+        if hdma.hblank && value & 0b1000_0000 == 0 {
+            hdma.hblank = false;
+            return;
+        }
+        hdma.blocks = value & 0x7F;
+        hdma.hblank = value & 0b1000_0000 != 0;
+        if !hdma.hblank {
+            for _ in 0..=hdma.blocks {
+                self.copy_vram_dma_block();
+            }
+        }
+    }
+    fn copy_vram_dma_block(&mut self) {
+        let HDMA {
+            source,
+            destination,
+            ..
+        } = self.io_registers.hdma;
+        let bank = self.io_registers.vbk.bank();
+        for offset in 0..16 {
+            let value = self.bus_read(source.wrapping_add(offset));
+            self.vram[bank].write(0x8000 + destination + offset, value);
+        }
+        let hdma = &mut self.io_registers.hdma;
+        hdma.source = source.wrapping_add(16);
+        hdma.destination = (destination + 16) & 0x1FF0;
+        hdma.stall += match self.io_registers.key1.double_speed() {
+            true => VRAM_DMA_BLOCK_CYCLES * 2,
+            false => VRAM_DMA_BLOCK_CYCLES,
+        };
+        hdma.blocks = hdma.blocks.wrapping_sub(1) & 0x7F;
+        if hdma.blocks == 0x7F {
+            hdma.hblank = false;
+        }
+    }
+    /// Copies the next block of an HBlank transfer as the PPU enters HBlank.
+    pub fn hblank_dma(&mut self) {
+        if self.io_registers.hdma.hblank {
+            self.copy_vram_dma_block();
+        }
+    }
+    /// T-cycles the CPU has to sit out for VRAM DMA, clearing them.
+    pub fn take_vram_dma_stall(&mut self) -> u32 {
+        std::mem::take(&mut self.io_registers.hdma.stall)
+    }
+    pub fn cartridge_mut(&mut self) -> Option<&mut Cartridge> {
+        self.cartridge.as_mut()
+    }
+    pub fn tick_timer(&mut self, cycles: u32) {
+        let io = &mut self.io_registers;
+        for _ in 0..cycles / 4 {
+            let double_speed = io.key1.double_speed();
+            let frame_sequencer_input = io.timer_and_divider.frame_sequencer_input(double_speed);
+            if io.timer_and_divider.tick(4) {
+                io.interrupt_flags.request(Interrupt::Timer);
+            }
+            if frame_sequencer_input && !io.timer_and_divider.frame_sequencer_input(double_speed) {
+                io.apu.clock_frame_sequencer();
+            }
+        }
+    }
+    pub fn tick_apu(&mut self, cycles: u32) {
+        self.io_registers.apu.tick(cycles);
+    }
+    pub fn tick_rtc(&mut self, cycles: u32) {
+        if let Some(cartridge) = &mut self.cartridge {
+            cartridge.tick_rtc(cycles);
+        }
+    }
+    /// Interrupts that are both requested in IF and enabled in IE.
+    pub fn pending_interrupts(&self) -> u8 {
+        self.io_registers.interrupt_flags.interrupt_flag & self.ie_register.read(0xFFFF) & 0x1F
+    }
+    /// Whether a button selected through P1 is held, which ends STOP.
+    pub fn joypad_input_low(&self) -> bool {
+        self.io_registers.joypad.read() & 0x0F != 0x0F
+    }
+    /// Brings the P1 input lines in line with the buttons held on `joypad`.
+    pub fn update_joypad(&mut self, joypad: &Joypad) {
+        let io = &mut self.io_registers;
+        if io
+            .joypad
+            .set_inputs(joypad.dpad_lines(), joypad.button_lines())
+        {
+            io.interrupt_flags.request(Interrupt::Joypad);
+        }
+    }
+    pub fn load_tiles(&self) -> Vec<Tile> {
         let mut i = 0_usize;
         let mut tiles: Vec<Tile> = Vec::new();
         while i <= 255 {
-            let tile = self
-                .vram
+            let tile = self.vram[0]
                 .tiledata
                 .get_tile(self.io_registers.lcdcontrol, i as u8);
             if !(tile == Tile::new([0; 16])) {
@@ -411,6 +1307,54 @@ impl MemoryMap {
     }
 }
 
+impl SaveState for MemoryMap {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_bool(self.cartridge.is_some());
+        if let Some(cartridge) = &self.cartridge {
+            cartridge.save_state(writer);
+        }
+        self.rom0.save_state(writer);
+        self.romx.save_state(writer);
+        for bank in &self.vram {
+            bank.save_state(writer);
+        }
+        self.sram.save_state(writer);
+        self.wram0.save_state(writer);
+        for bank in &self.wramx {
+            bank.save_state(writer);
+        }
+        self.echo.save_state(writer);
+        self.aom.save_state(writer);
+        self.unused.save_state(writer);
+        self.io_registers.save_state(writer);
+        self.hram.save_state(writer);
+        self.ie_register.save_state(writer);
+    }
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), SaveStateError> {
+        match (reader.read_bool()?, &mut self.cartridge) {
+            (true, Some(cartridge)) => cartridge.load_state(reader)?,
+            (false, None) => (),
+            _ => return Err(SaveStateError::CartridgeMismatch),
+        }
+        self.rom0.load_state(reader)?;
+        self.romx.load_state(reader)?;
+        for bank in &mut self.vram {
+            bank.load_state(reader)?;
+        }
+        self.sram.load_state(reader)?;
+        self.wram0.load_state(reader)?;
+        for bank in &mut self.wramx {
+            bank.load_state(reader)?;
+        }
+        self.echo.load_state(reader)?;
+        self.aom.load_state(reader)?;
+        self.unused.load_state(reader)?;
+        self.io_registers.load_state(reader)?;
+        self.hram.load_state(reader)?;
+        self.ie_register.load_state(reader)
+    }
+}
+
 impl Default for MemoryMap {
     fn default() -> Self {
         Self::new()
@@ -0,0 +1,181 @@
+//! Save states are a flat little endian binary format:
+//!
+//! | offset  | size | contents                          |
+//! |---------|------|-----------------------------------|
+//! | 0       | 8    | magic, `STATE_MAGIC`              |
+//! | 8       | 4    | format version, `STATE_VERSION`   |
+//! | 12      | 4    | payload length `n`                |
+//! | 16      | n    | payload                           |
+//! | 16 + n  | 4    | CRC-32 (IEEE) of the payload      |
+//!
+//! The payload is each component written back to back in a fixed order:
+//! the cycle counter, the CPU, the memory map and finally the display.
+//! Every component writes its fields in declaration order, booleans as a
+//! single byte and multi byte values little endian. Variable length data
+//! such as cartridge RAM is prefixed with its length as a u32. The cartridge
+//! ROM is not stored, only its checksums so a state can't be loaded into a
+//! different game.
+//!
+//! The version is bumped whenever the payload layout changes. Only states
+//! with exactly the current version are accepted.
+use std::fmt;
+
+pub const STATE_MAGIC: [u8; 8] = *b"CRABBOY\x1A";
+pub const STATE_VERSION: u32 = 1;
+
+const HEADER_SIZE: usize = 16;
+const CHECKSUM_SIZE: usize = 4;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum SaveStateError {
+    BadMagic,
+    UnsupportedVersion(u32),
+    Truncated,
+    ChecksumMismatch,
+    Corrupt(&'static str),
+    CartridgeMismatch,
+}
+impl fmt::Display for SaveStateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SaveStateError::BadMagic => write!(f, "not a save state"),
+            SaveStateError::UnsupportedVersion(version) => write!(
+                f,
+                "save state version {version} is not supported, expected version {STATE_VERSION}"
+            ),
+            SaveStateError::Truncated => write!(f, "save state is truncated"),
+            SaveStateError::ChecksumMismatch => write!(f, "save state checksum does not match"),
+            SaveStateError::Corrupt(reason) => write!(f, "save state is corrupt: {reason}"),
+            SaveStateError::CartridgeMismatch => {
+                write!(f, "save state was made with a different cartridge")
+            }
+        }
+    }
+}
+impl std::error::Error for SaveStateError {}
+
+pub(crate) trait SaveState {
+    fn save_state(&self, writer: &mut StateWriter);
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), SaveStateError>;
+}
+
+#[derive(Default)]
+pub(crate) struct StateWriter {
+    bytes: Vec<u8>,
+}
+impl StateWriter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    pub fn write_u8(&mut self, value: u8) {
+        self.bytes.push(value);
+    }
+    pub fn write_bool(&mut self, value: bool) {
+        self.bytes.push(value as u8);
+    }
+    pub fn write_u16(&mut self, value: u16) {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+    pub fn write_u32(&mut self, value: u32) {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+    pub fn write_u64(&mut self, value: u64) {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+    pub fn write_bytes(&mut self, bytes: &[u8]) {
+        self.bytes.extend_from_slice(bytes);
+    }
+    /// Wraps the payload in the header and checksum.
+    pub fn finish(self) -> Vec<u8> {
+        let mut state = Vec::with_capacity(HEADER_SIZE + self.bytes.len() + CHECKSUM_SIZE);
+        state.extend_from_slice(&STATE_MAGIC);
+        state.extend_from_slice(&STATE_VERSION.to_le_bytes());
+        state.extend_from_slice(&(self.bytes.len() as u32).to_le_bytes());
+        state.extend_from_slice(&self.bytes);
+        state.extend_from_slice(&crc32(&self.bytes).to_le_bytes());
+        state
+    }
+}
+
+pub(crate) struct StateReader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+impl<'a> StateReader<'a> {
+    /// Checks the header and checksum and returns a reader over the payload.
+    pub fn new(state: &'a [u8]) -> Result<Self, SaveStateError> {
+        if state.len() < STATE_MAGIC.len() || state[..STATE_MAGIC.len()] != STATE_MAGIC {
+            return Err(SaveStateError::BadMagic);
+        }
+        if state.len() < HEADER_SIZE {
+            return Err(SaveStateError::Truncated);
+        }
+        let version = u32::from_le_bytes(state[8..12].try_into().unwrap());
+        if version != STATE_VERSION {
+            return Err(SaveStateError::UnsupportedVersion(version));
+        }
+        let length = u32::from_le_bytes(state[12..16].try_into().unwrap()) as usize;
+        if state.len() < HEADER_SIZE + length + CHECKSUM_SIZE {
+            return Err(SaveStateError::Truncated);
+        }
+        if state.len() > HEADER_SIZE + length + CHECKSUM_SIZE {
+            return Err(SaveStateError::Corrupt("trailing data after checksum"));
+        }
+        let payload = &state[HEADER_SIZE..HEADER_SIZE + length];
+        let checksum = u32::from_le_bytes(state[HEADER_SIZE + length..].try_into().unwrap());
+        if crc32(payload) != checksum {
+            return Err(SaveStateError::ChecksumMismatch);
+        }
+        Ok(Self {
+            bytes: payload,
+            position: 0,
+        })
+    }
+    fn take(&mut self, length: usize) -> Result<&'a [u8], SaveStateError> {
+        let bytes = self
+            .bytes
+            .get(self.position..self.position + length)
+            .ok_or(SaveStateError::Truncated)?;
+        self.position += length;
+        Ok(bytes)
+    }
+    pub fn read_u8(&mut self) -> Result<u8, SaveStateError> {
+        Ok(self.take(1)?[0])
+    }
+    pub fn read_bool(&mut self) -> Result<bool, SaveStateError> {
+        match self.read_u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(SaveStateError::Corrupt("invalid boolean")),
+        }
+    }
+    pub fn read_u16(&mut self) -> Result<u16, SaveStateError> {
+        Ok(u16::from_le_bytes(self.take(2)?.try_into().unwrap()))
+    }
+    pub fn read_u32(&mut self) -> Result<u32, SaveStateError> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+    pub fn read_u64(&mut self) -> Result<u64, SaveStateError> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+    pub fn read_bytes(&mut self, bytes: &mut [u8]) -> Result<(), SaveStateError> {
+        bytes.copy_from_slice(self.take(bytes.len())?);
+        Ok(())
+    }
+    /// Fails unless the whole payload was consumed.
+    pub fn finish(self) -> Result<(), SaveStateError> {
+        match self.position == self.bytes.len() {
+            true => Ok(()),
+            false => Err(SaveStateError::Corrupt("unexpected data at end of payload")),
+        }
+    }
+}
+
+fn crc32(bytes: &[u8]) -> u32 {
+    !bytes.iter().fold(0xFFFF_FFFF_u32, |crc, byte| {
+        (0..8).fold(crc ^ *byte as u32, |crc, _| match crc & 1 {
+            1 => (crc >> 1) ^ 0xEDB8_8320,
+            _ => crc >> 1,
+        })
+    })
+}